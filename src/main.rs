@@ -1,16 +1,17 @@
 #![allow(clippy::missing_safety_doc)]
 #![allow(non_snake_case)]
 
+use crate::utils::checkpoint::Progress;
 use crate::utils::conv3x3::Conv3x3;
-use crate::utils::dataloader::DataLoader;
 use crate::utils::head::SoftMaxCrossEntropy;
 use crate::utils::linear::LinearLayer;
 use crate::utils::maxpool2x2::MaxPool2x2;
 use crate::utils::mnist::MnistData;
 use crate::utils::network::Network;
-use crate::utils::nn_trait::{DataSet, Layer};
+use crate::utils::nn_trait::Layer;
 use crate::utils::optimizer::SGD;
 use crate::utils::relu::ReluLayer;
+use crate::utils::trainer::{loader_seed, Trainer};
 
 pub mod utils;
 
@@ -37,48 +38,55 @@ fn main() {
         let decay = 0.0001f32;
 
         let opt = Box::new(SGD::new(rate, momentum, decay));
-        let mut network = Network::new(layers, head, opt);
+        let network = Network::new(layers, head, opt);
 
-        for i in 0..1 {
-            let mut iter = 0;
-            let dataloader = DataLoader::new(&train_dataset, 128, i << 10);
-            for (image, gt) in dataloader {
-                iter += 1;
-                let pred = network.forward(image);
-                let loss = network.calc_loss(pred, gt);
+        // checkpoints and the exported model are only written when a directory is given
+        let output_dir = std::env::args().nth(1).map(std::path::PathBuf::from);
+        let output = move |name: &str| {
+            output_dir
+                .as_ref()
+                .map(|dir| dir.join(name).to_string_lossy().into_owned())
+        };
 
-                let (h, _) = loss.shape();
-                let sum = (0..h)
-                    .into_iter()
-                    .map(|idx| loss.at(idx as isize, 0))
-                    .fold(0f32, |a, b| a + b);
-                if iter % 10 == 0 {
-                    println!("epoch {}, iter {}, loss {}", i, iter, sum / h as f32);
-                }
-                network.backward(loss);
-                network.update_parameters();
+        let mut trainer = Trainer::new(network, train_dataset, test_dataset, 128);
+        trainer.on_batch_end(|_, stats| {
+            if stats.batch.is_multiple_of(10) {
+                println!(
+                    "epoch {}, iter {}, loss {}, grad norm {}",
+                    stats.epoch, stats.batch, stats.loss, stats.grad_norm
+                );
             }
-            println!("testing");
-            let dataloader = DataLoader::new(&test_dataset, 1024, 0);
-            let mut ok = 0;
-            for (image, gt) in dataloader {
-                let pred = network.forward(image);
-                let result = network.get_result(pred);
-                let sz = result.len();
-                (0..sz).into_iter().for_each(|idx| {
-                    let res = *result.get(idx).unwrap();
-                    if (*gt.row_at(idx as isize).add(res) - 1.0).abs() < 1e-5 {
-                        ok += 1;
-                    }
-                });
+        });
+        let checkpoint = output("mnist.tnts");
+        let seed = trainer.seed;
+        trainer.on_epoch_end(move |network, stats| {
+            match stats.valid_accuracy {
+                Some(accuracy) => println!(
+                    "epoch {}, test loss {:.4}, acc {:.4}%",
+                    stats.epoch,
+                    stats.valid_loss,
+                    accuracy * 100.0
+                ),
+                None => println!("epoch {}, test loss {:.4}", stats.epoch, stats.valid_loss),
             }
-            println!(
-                "epoch {},acc [{}/{}],{:.4}%",
-                i,
-                ok,
-                test_dataset.len(),
-                ok as f32 / test_dataset.len() as f32 * 100.0
-            )
+            let Some(path) = &checkpoint else {
+                return;
+            };
+            let progress = Progress {
+                epoch: stats.epoch + 1,
+                iteration: stats.iteration,
+                loader_seed: loader_seed(seed, stats.epoch + 1),
+                loader_position: 0,
+            };
+            network.save_training(path, &progress, &[]).unwrap();
+        });
+        trainer.fit(1).unwrap();
+        let network = &mut trainer.network;
+        if let Some(path) = output("mnist.tnnw") {
+            network.save(&path).unwrap();
+        }
+        if let Some(path) = output("mnist.onnx") {
+            network.export_onnx(&path).unwrap();
         }
     }
 }
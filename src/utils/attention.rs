@@ -0,0 +1,239 @@
+use crate::utils::linear::LinearLayer;
+use crate::utils::mat::Matrix;
+use crate::utils::misc::{merge_rows, split_rows, zeros};
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Layer;
+use crate::utils::parameter::{prefix_parameters, Parameter};
+use rayon::prelude::*;
+
+// self attention over a B*(TD) input, heads are contiguous D/heads wide slices of a token
+pub struct MultiHeadAttention {
+    pub d_model: usize,
+    pub num_heads: usize,
+    pub seq_len: usize,
+    pub causal: bool,
+    // B*T, 1 for tokens to attend and 0 for padding, ignored when null
+    pub padding_mask: Matrix,
+
+    pub query: LinearLayer,
+    pub key: LinearLayer,
+    pub value: LinearLayer,
+    pub output: LinearLayer,
+
+    last_q: Matrix,
+    last_k: Matrix,
+    last_v: Matrix,
+    // softmax scores, B*heads*T rows of T
+    last_p: Matrix,
+}
+
+impl MultiHeadAttention {
+    pub unsafe fn new(d_model: usize, num_heads: usize, seq_len: usize, causal: bool) -> Self {
+        if !d_model.is_multiple_of(num_heads) {
+            panic!("d_model must be divisible by num_heads");
+        }
+        let mut ret = Self {
+            d_model,
+            num_heads,
+            seq_len,
+            causal,
+            padding_mask: Matrix::null(),
+            query: LinearLayer::new(d_model, d_model),
+            key: LinearLayer::new(d_model, d_model),
+            value: LinearLayer::new(d_model, d_model),
+            output: LinearLayer::new(d_model, d_model),
+            last_q: Matrix::null(),
+            last_k: Matrix::null(),
+            last_v: Matrix::null(),
+            last_p: Matrix::null(),
+        };
+        prefix_parameters(ret.query.parameters(), "query");
+        prefix_parameters(ret.key.parameters(), "key");
+        prefix_parameters(ret.value.parameters(), "value");
+        prefix_parameters(ret.output.parameters(), "output");
+        ret
+    }
+
+    fn masked(&self, batch: usize, i: usize, j: usize) -> bool {
+        unsafe {
+            (self.causal && j > i)
+                || (!self.padding_mask.is_null()
+                    && self.padding_mask.at(batch as isize, j as isize) < 0.5)
+        }
+    }
+
+    // softmax(QK^T / sqrt(d)) V for every (batch, head), BT*D
+    unsafe fn attend(&mut self, h: usize) -> Matrix {
+        let t = self.seq_len;
+        let heads = self.num_heads;
+        let dh = self.d_model / heads;
+        let scale = 1.0 / (dh as f32).sqrt();
+        let ret = zeros(h * t, self.d_model);
+        self.last_p = Matrix::new(h * heads * t, t);
+        (0..h * heads).into_par_iter().for_each(|task| {
+            let b = task / heads;
+            let o = task % heads * dh;
+            for i in 0..t {
+                let q = self.last_q.row_at((b * t + i) as isize).add(o);
+                let p = self.last_p.row_at((task * t + i) as isize);
+                for j in 0..t {
+                    *p.add(j) = if self.masked(b, i, j) {
+                        -1e9
+                    } else {
+                        let k = self.last_k.row_at((b * t + j) as isize).add(o);
+                        (0..dh).map(|x| *q.add(x) * *k.add(x)).sum::<f32>() * scale
+                    };
+                }
+                let max_val = (1..t).map(|j| *p.add(j)).fold(*p, f32::max);
+                let mut sum = 0.0;
+                for j in 0..t {
+                    let now = (*p.add(j) - max_val).exp();
+                    *p.add(j) = now;
+                    sum += now;
+                }
+                let dst = ret.row_at((b * t + i) as isize).add(o);
+                for j in 0..t {
+                    *p.add(j) /= sum;
+                    let v = self.last_v.row_at((b * t + j) as isize).add(o);
+                    for x in 0..dh {
+                        *dst.add(x) += *p.add(j) * *v.add(x);
+                    }
+                }
+            }
+        });
+        ret
+    }
+}
+
+impl nn_trait::Layer for MultiHeadAttention {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            if w != self.seq_len * self.d_model {
+                panic!("call MultiHeadAttention with unmatched input shape");
+            }
+            if !self.padding_mask.is_null() && self.padding_mask.shape() != (h, self.seq_len) {
+                panic!("call MultiHeadAttention with unmatched padding_mask shape");
+            }
+            let tokens = split_rows(&input, self.d_model);
+            self.last_q = self.query.forward(tokens.clone());
+            self.last_k = self.key.forward(tokens.clone());
+            self.last_v = self.value.forward(tokens);
+            let res = self.attend(h);
+            merge_rows(&self.output.forward(res), self.seq_len)
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let t = self.seq_len;
+            let heads = self.num_heads;
+            let dh = self.d_model / heads;
+            let scale = 1.0 / (dh as f32).sqrt();
+            let d_res = self.output.backward(split_rows(&dLoss, self.d_model));
+            let dq = zeros(h * t, self.d_model);
+            let dk = zeros(h * t, self.d_model);
+            let dv = zeros(h * t, self.d_model);
+            (0..h * heads).into_par_iter().for_each(|task| {
+                let b = task / heads;
+                let o = task % heads * dh;
+                let mut dp = vec![0f32; t];
+                for i in 0..t {
+                    let p = self.last_p.row_at((task * t + i) as isize);
+                    let dr = d_res.row_at((b * t + i) as isize).add(o);
+                    for (j, dpj) in dp.iter_mut().enumerate() {
+                        let row = (b * t + j) as isize;
+                        let v = self.last_v.row_at(row).add(o);
+                        let dvj = dv.row_at(row).add(o);
+                        let pij = *p.add(j);
+                        let mut acc = 0.0;
+                        for x in 0..dh {
+                            acc += *dr.add(x) * *v.add(x);
+                            *dvj.add(x) += pij * *dr.add(x);
+                        }
+                        *dpj = acc;
+                    }
+                    let sum = (0..t).map(|j| *p.add(j) * dp[j]).sum::<f32>();
+                    let q = self.last_q.row_at((b * t + i) as isize).add(o);
+                    let dqi = dq.row_at((b * t + i) as isize).add(o);
+                    for (j, dpj) in dp.iter().enumerate() {
+                        let ds = *p.add(j) * (dpj - sum) * scale;
+                        let row = (b * t + j) as isize;
+                        let k = self.last_k.row_at(row).add(o);
+                        let dkj = dk.row_at(row).add(o);
+                        for x in 0..dh {
+                            *dqi.add(x) += ds * *k.add(x);
+                            *dkj.add(x) += ds * *q.add(x);
+                        }
+                    }
+                }
+            });
+            let ret = self.query.backward(dq);
+            ret.add(&self.key.backward(dk), true);
+            ret.add(&self.value.backward(dv), true);
+            merge_rows(&ret, t)
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut ret = self.query.parameters();
+        ret.extend(self.key.parameters());
+        ret.extend(self.value.parameters());
+        ret.extend(self.output.parameters());
+        ret
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "MultiHeadAttention",
+            &[
+                ("d_model", self.d_model),
+                ("num_heads", self.num_heads),
+                ("seq_len", self.seq_len),
+                ("causal", self.causal as usize),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_layer, rand_mat};
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let x = rand_mat(3, 12, &mut seed);
+            for causal in [false, true] {
+                let mut attention = MultiHeadAttention::new(4, 2, 3, causal);
+                for linear in [
+                    &mut attention.query,
+                    &mut attention.key,
+                    &mut attention.value,
+                    &mut attention.output,
+                ] {
+                    linear.weight.value = rand_mat(4, 4, &mut seed);
+                }
+                // the last token of the second sample is padding
+                let mask = Matrix::new(3, 3);
+                mask.fill_(1.0);
+                *mask.row_at(1).add(2) = 0.0;
+                attention.padding_mask = mask;
+                assert!(check_layer(&mut attention, &x) < 2e-2);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unmatched padding_mask shape")]
+    fn narrow_padding_mask_is_refused() {
+        unsafe {
+            let mut seed = 5u32;
+            let mut attention = MultiHeadAttention::new(4, 2, 3, false);
+            let mask = Matrix::new(3, 2);
+            mask.fill_(1.0);
+            attention.padding_mask = mask;
+            attention.forward(rand_mat(3, 12, &mut seed));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::assign;
+use crate::utils::network::Network;
+use crate::utils::parameter::Parameter;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Averaging {
+    // shadow = decay * shadow + (1 - decay) * value after every update
+    // with warmup the decay is min(decay, (1 + n) / (10 + n)) at the n-th update,
+    // so the early random weights are forgotten quickly
+    Exponential { decay: f32, warmup: bool },
+    // SWA, equal weight mean of the weights after every `every` updates from update `start` on
+    Stochastic { start: usize, every: usize },
+}
+
+// a network with a shadow copy of every trainable parameter, averaged over the training
+pub struct AveragedNetwork {
+    pub network: Network,
+    pub averaging: Averaging,
+    // in the order of network.parameters(), trainable ones only
+    shadow: Vec<Matrix>,
+    updates: usize,
+    averaged: usize,
+    swapped: bool,
+}
+
+fn trainable(network: &mut Network) -> Vec<&mut Parameter> {
+    network
+        .parameters()
+        .into_iter()
+        .filter(|p| p.requires_grad)
+        .collect()
+}
+
+impl AveragedNetwork {
+    pub unsafe fn new(mut network: Network, averaging: Averaging) -> Self {
+        let shadow = trainable(&mut network)
+            .iter()
+            .map(|p| p.value.clone())
+            .collect();
+        Self {
+            network,
+            averaging,
+            shadow,
+            updates: 0,
+            averaged: 0,
+            swapped: false,
+        }
+    }
+
+    // the optimizer step followed by the average update, returns the pre-clip grad norm
+    pub unsafe fn update_parameters(&mut self) -> f32 {
+        if self.swapped {
+            panic!("swap the shadow weights out before training");
+        }
+        let norm = self.network.update_parameters();
+        self.update_average();
+        norm
+    }
+
+    pub unsafe fn update_average(&mut self) {
+        if self.swapped {
+            panic!("swap the shadow weights out before training");
+        }
+        self.updates += 1;
+        let weight = match self.averaging {
+            Averaging::Exponential { decay, warmup } => {
+                let n = self.updates as f32;
+                let decay = if warmup {
+                    decay.min((1.0 + n) / (10.0 + n))
+                } else {
+                    decay
+                };
+                1.0 - decay
+            }
+            Averaging::Stochastic { start, every } => {
+                if self.updates < start || !(self.updates - start).is_multiple_of(every.max(1)) {
+                    return;
+                }
+                // the first average replaces the initial copy
+                1.0 / (self.averaged + 1) as f32
+            }
+        };
+        self.averaged += 1;
+        let parameters = trainable(&mut self.network);
+        if parameters.len() != self.shadow.len() {
+            panic!("trainable parameters changed after AveragedNetwork was built");
+        }
+        for (shadow, parameter) in self.shadow.iter().zip(parameters) {
+            shadow.mul_with_numeric(1.0 - weight, true);
+            shadow.add(
+                &parameter.value.mul_with_numeric(weight, false).unwrap(),
+                true,
+            );
+        }
+    }
+
+    // how many times the weights went into the average
+    pub fn averaged(&self) -> usize {
+        self.averaged
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        self.swapped
+    }
+
+    // exchanges the network weights with the shadow copies
+    unsafe fn exchange(&mut self) {
+        let parameters = trainable(&mut self.network);
+        if parameters.len() != self.shadow.len() {
+            panic!("trainable parameters changed after AveragedNetwork was built");
+        }
+        for (shadow, parameter) in self.shadow.iter_mut().zip(parameters) {
+            let current = parameter.value.clone();
+            assign(&parameter.value, shadow);
+            *shadow = current;
+        }
+        self.swapped = !self.swapped;
+    }
+
+    // puts the averaged weights into the network, the training weights are kept aside
+    pub unsafe fn swap_in(&mut self) {
+        if self.swapped {
+            panic!("the shadow weights are already swapped in");
+        }
+        self.exchange();
+    }
+
+    // puts the training weights back
+    pub unsafe fn swap_out(&mut self) {
+        if !self.swapped {
+            panic!("the shadow weights are not swapped in");
+        }
+        self.exchange();
+    }
+
+    // runs f on the network with the averaged weights in, e.g. an evaluation pass
+    pub unsafe fn with_averaged<R>(&mut self, f: impl FnOnce(&mut Network) -> R) -> R {
+        self.swap_in();
+        let ret = f(&mut self.network);
+        self.swap_out();
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::head::SoftMaxCrossEntropy;
+    use crate::utils::linear::LinearLayer;
+    use crate::utils::nn_trait::Layer;
+    use crate::utils::optimizer::SGD;
+
+    // a 1x1 weight and bias, both starting at 0
+    unsafe fn averaged(averaging: Averaging) -> AveragedNetwork {
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(LinearLayer::new(1, 1))];
+        let mut network = Network::new(
+            layers,
+            Box::new(SoftMaxCrossEntropy::new()),
+            Box::new(SGD::new(0.1, 0.0, 0.0)),
+        );
+        set(&mut network, 0.0);
+        AveragedNetwork::new(network, averaging)
+    }
+
+    unsafe fn set(network: &mut Network, value: f32) {
+        for parameter in network.parameters() {
+            parameter.value.fill_(value);
+        }
+    }
+
+    unsafe fn values(network: &mut Network) -> Vec<f32> {
+        network
+            .parameters()
+            .iter()
+            .map(|p| p.value.at(0, 0))
+            .collect()
+    }
+
+    // the weights after the n-th update are n, returns the average
+    unsafe fn average_of_counts(averaging: Averaging, updates: usize) -> (f32, usize) {
+        let mut avg = averaged(averaging);
+        for n in 1..=updates {
+            set(&mut avg.network, n as f32);
+            avg.update_average();
+        }
+        let ret = avg.with_averaged(|network| values(network)[0]);
+        (ret, avg.averaged())
+    }
+
+    #[test]
+    fn exponential_average_warms_up() {
+        unsafe {
+            let warmup = Averaging::Exponential {
+                decay: 0.99,
+                warmup: true,
+            };
+            // decays of 2 / 11 and then 3 / 12
+            let first = 9.0 / 11.0;
+            let (got, _) = average_of_counts(warmup, 2);
+            assert!((got - (0.25 * first + 0.75 * 2.0)).abs() < 1e-6);
+            let (got, _) = average_of_counts(warmup, 1);
+            assert!((got - first).abs() < 1e-6);
+            // the full decay from the start without warmup
+            let plain = Averaging::Exponential {
+                decay: 0.99,
+                warmup: false,
+            };
+            let (got, _) = average_of_counts(plain, 2);
+            assert!((got - (0.99 * 0.01 + 0.01 * 2.0)).abs() < 1e-6);
+            // warmup stops once (1 + n) / (10 + n) passes the decay
+            let (got, _) = average_of_counts(
+                Averaging::Exponential {
+                    decay: 0.1,
+                    warmup: true,
+                },
+                1,
+            );
+            assert!((got - 0.9).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn stochastic_average_is_the_running_mean() {
+        unsafe {
+            // updates 2, 4 and 6 go in, the initial copy does not count
+            let swa = Averaging::Stochastic { start: 2, every: 2 };
+            let (got, count) = average_of_counts(swa, 7);
+            assert_eq!(count, 3);
+            assert!((got - 4.0).abs() < 1e-6);
+            let (got, count) = average_of_counts(swa, 1);
+            assert_eq!(count, 0);
+            assert_eq!(got, 0.0);
+        }
+    }
+
+    #[test]
+    fn swapping_restores_the_live_weights() {
+        unsafe {
+            let mut avg = averaged(Averaging::Stochastic { start: 1, every: 1 });
+            set(&mut avg.network, 2.0);
+            avg.update_average();
+            set(&mut avg.network, 4.0);
+            avg.update_average();
+            set(&mut avg.network, 5.0);
+
+            avg.swap_in();
+            assert!(avg.is_swapped());
+            assert_eq!(values(&mut avg.network), vec![3.0, 3.0]);
+            avg.swap_out();
+            assert!(!avg.is_swapped());
+            assert_eq!(values(&mut avg.network), vec![5.0, 5.0]);
+
+            // the average is kept across a swap
+            let got = avg.with_averaged(|network| values(network));
+            assert_eq!(got, vec![3.0, 3.0]);
+            assert_eq!(values(&mut avg.network), vec![5.0, 5.0]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "swap the shadow weights out before training")]
+    fn training_with_the_average_in_is_refused() {
+        unsafe {
+            let mut avg = averaged(Averaging::Stochastic { start: 1, every: 1 });
+            avg.swap_in();
+            avg.update_parameters();
+        }
+    }
+}
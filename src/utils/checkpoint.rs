@@ -0,0 +1,193 @@
+use crate::utils::mat::Matrix;
+use std::io::{Error, ErrorKind, Result};
+
+// bumped whenever the layout of any checkpoint kind changes
+pub const VERSION: u32 = 2;
+
+// a file is magic, version, body and the FNV-1a 64 of everything before the checksum
+// all numbers are little endian, strings are a u32 byte length and utf-8
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+pub struct Reader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+// state that has to survive a restart for a resumed run to match the uninterrupted one
+pub trait Stateful {
+    fn write_state(&self, w: &mut Writer);
+    fn read_state(&mut self, r: &mut Reader) -> Result<()>;
+}
+
+// where a training run stopped, the data loader is rebuilt from seed and position
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Progress {
+    pub epoch: usize,
+    // updates since the start of the run
+    pub iteration: usize,
+    pub loader_seed: u32,
+    // samples of this epoch already handed out
+    pub loader_position: usize,
+}
+
+impl Stateful for Progress {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_u64(self.epoch as u64);
+        w.put_u64(self.iteration as u64);
+        w.put_u32(self.loader_seed);
+        w.put_u64(self.loader_position as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        *self = Self {
+            epoch: r.get_u64()? as usize,
+            iteration: r.get_u64()? as usize,
+            loader_seed: r.get_u32()?,
+            loader_position: r.get_u64()? as usize,
+        };
+        Ok(())
+    }
+}
+
+pub fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Writer {
+    pub fn new(magic: &[u8; 4]) -> Self {
+        let mut ret = Self { buf: Vec::new() };
+        ret.buf.extend_from_slice(magic);
+        ret.put_u32(VERSION);
+        ret
+    }
+    pub fn put_u32(&mut self, x: u32) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+    pub fn put_u64(&mut self, x: u64) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+    pub fn put_f32(&mut self, x: f32) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+    pub fn put_bool(&mut self, x: bool) {
+        self.buf.push(x as u8);
+    }
+    pub fn put_str(&mut self, x: &str) {
+        self.put_u32(x.len() as u32);
+        self.buf.extend_from_slice(x.as_bytes());
+    }
+    // rows, cols and the values row by row, without the padding
+    pub unsafe fn put_matrix(&mut self, x: &Matrix) {
+        let (h, w) = x.shape();
+        self.put_u32(h as u32);
+        self.put_u32(w as u32);
+        for i in 0..h {
+            let row = std::slice::from_raw_parts(x.row_at(i as isize), w);
+            for &v in row {
+                self.put_f32(v);
+            }
+        }
+    }
+    pub fn save(mut self, path: &str) -> Result<()> {
+        let sum = checksum(&self.buf);
+        self.put_u64(sum);
+        std::fs::write(path, &self.buf)
+    }
+}
+
+impl Reader {
+    // checks the magic, the version and the checksum before anything is read
+    pub fn open(path: &str, magic: &[u8; 4]) -> Result<Self> {
+        let mut buf = std::fs::read(path)?;
+        if buf.len() < 16 || &buf[..4] != magic {
+            return Err(invalid(format!(
+                "{} is not a {} checkpoint",
+                path,
+                String::from_utf8_lossy(magic)
+            )));
+        }
+        let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(format!(
+                "unsupported checkpoint version {}, expected {}",
+                version, VERSION
+            )));
+        }
+        let body = buf.len() - 8;
+        let sum = u64::from_le_bytes(buf[body..].try_into().unwrap());
+        if checksum(&buf[..body]) != sum {
+            return Err(invalid(format!("{} is corrupted, checksum mismatch", path)));
+        }
+        buf.truncate(body);
+        let ret = Self { buf, pos: 8 };
+        Ok(ret)
+    }
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(invalid("checkpoint ends early".to_string()));
+        }
+        self.pos += len;
+        Ok(&self.buf[self.pos - len..self.pos])
+    }
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn get_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn get_bool(&mut self) -> Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("checkpoint holds a broken flag".to_string())),
+        }
+    }
+    // state sections start with the name of their owner
+    pub fn expect_str(&mut self, expected: &str) -> Result<()> {
+        let found = self.get_str()?;
+        if found != expected {
+            return Err(invalid(format!(
+                "checkpoint holds {} state, expected {}",
+                found, expected
+            )));
+        }
+        Ok(())
+    }
+    pub fn get_str(&mut self) -> Result<String> {
+        let len = self.get_u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| invalid("checkpoint holds a broken string".to_string()))
+    }
+    pub unsafe fn get_matrix(&mut self) -> Result<Matrix> {
+        let h = self.get_u32()? as usize;
+        let w = self.get_u32()? as usize;
+        if h.saturating_mul(w).saturating_mul(4) > self.buf.len() - self.pos {
+            return Err(invalid("checkpoint ends early".to_string()));
+        }
+        let ret = Matrix::new(h, w);
+        for i in 0..h {
+            let row = ret.row_at(i as isize);
+            for j in 0..w {
+                *row.add(j) = self.get_f32()?;
+            }
+        }
+        Ok(ret)
+    }
+    // everything has to be consumed, trailing bytes mean a different model wrote the file
+    pub fn finish(&self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            return Err(invalid("checkpoint holds more than this model".to_string()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::utils::misc::{l2_norm, squared_sum};
+use crate::utils::parameter::Parameter;
+
+// applied by Network to every grad before the optimizer step
+#[derive(Clone, Copy, PartialEq)]
+pub enum GradientClip {
+    Off,
+    // every element into [-x, x]
+    Value(f32),
+    // every parameter's grad scaled down to an L2 norm of at most x
+    Norm(f32),
+    // all grads scaled by the same factor down to a joint L2 norm of at most x
+    GlobalNorm(f32),
+}
+
+pub unsafe fn grad_norm(parameter: &Parameter) -> f32 {
+    l2_norm(&parameter.grad)
+}
+
+// L2 norm of all the grads taken as one vector
+pub unsafe fn global_grad_norm(parameters: &[&mut Parameter]) -> f32 {
+    parameters
+        .iter()
+        .map(|p| squared_sum(&p.grad))
+        .sum::<f32>()
+        .sqrt()
+}
+
+// returns the global norm from before clipping
+pub unsafe fn clip_gradients(parameters: &[&mut Parameter], clip: GradientClip) -> f32 {
+    let norm = global_grad_norm(parameters);
+    match clip {
+        GradientClip::Off => {}
+        GradientClip::Value(x) => {
+            for parameter in parameters {
+                parameter.grad.clamp(-x, x);
+            }
+        }
+        GradientClip::Norm(x) => {
+            for parameter in parameters {
+                let norm = grad_norm(parameter);
+                if norm > x {
+                    parameter.grad.mul_with_numeric(x / norm, true);
+                }
+            }
+        }
+        GradientClip::GlobalNorm(x) => {
+            if norm > x {
+                for parameter in parameters {
+                    parameter.grad.mul_with_numeric(x / norm, true);
+                }
+            }
+        }
+    }
+    norm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mat::Matrix;
+
+    unsafe fn parameter(values: [f32; 2]) -> Parameter {
+        let value = Matrix::new(1, 2);
+        value.fill_(0.0);
+        let ret = Parameter::new("weight", value, true);
+        *ret.grad.row_at(0) = values[0];
+        *ret.grad.row_at(0).add(1) = values[1];
+        ret
+    }
+
+    // grads of norm 5 and 12, 13 together
+    unsafe fn clipped(clip: GradientClip) -> (f32, [f32; 4]) {
+        let mut a = parameter([3.0, 4.0]);
+        let mut b = parameter([0.0, 12.0]);
+        let norm = clip_gradients(&[&mut a, &mut b], clip);
+        (
+            norm,
+            [
+                a.grad.at(0, 0),
+                a.grad.at(0, 1),
+                b.grad.at(0, 0),
+                b.grad.at(0, 1),
+            ],
+        )
+    }
+
+    fn assert_close(got: [f32; 4], expected: [f32; 4]) {
+        for (g, e) in got.iter().zip(expected.iter()) {
+            assert!((g - e).abs() < 1e-5, "{:?} != {:?}", got, expected);
+        }
+    }
+
+    #[test]
+    fn clips_and_returns_the_norm_from_before() {
+        unsafe {
+            for (clip, expected) in [
+                (GradientClip::Off, [3.0, 4.0, 0.0, 12.0]),
+                (GradientClip::Value(3.5), [3.0, 3.5, 0.0, 3.5]),
+                // only the second grad is over 6
+                (GradientClip::Norm(6.0), [3.0, 4.0, 0.0, 6.0]),
+                // both halved, keeping their direction together
+                (GradientClip::GlobalNorm(6.5), [1.5, 2.0, 0.0, 6.0]),
+                (GradientClip::GlobalNorm(20.0), [3.0, 4.0, 0.0, 12.0]),
+            ] {
+                let (norm, grads) = clipped(clip);
+                assert!((norm - 13.0).abs() < 1e-5);
+                assert_close(grads, expected);
+            }
+        }
+    }
+}
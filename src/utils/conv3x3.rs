@@ -1,5 +1,6 @@
 use crate::utils::mat::Matrix;
 use crate::utils::nn_trait;
+use crate::utils::parameter::Parameter;
 use rayon::prelude::*;
 use std::arch::x86_64;
 use std::mem::size_of;
@@ -15,12 +16,8 @@ pub struct Conv3x3 {
     pub feat_row: usize,
     pub feat_col: usize,
 
-    pub weight: Matrix,
-    pub bias: Matrix,
-    pub d_weight: Matrix,
-    pub d_bias: Matrix,
-    pub v_weight: Matrix,
-    pub v_bias: Matrix,
+    pub weight: Parameter,
+    pub bias: Parameter,
 
     pub pinned_memory_for_im2col: Matrix,
 
@@ -51,16 +48,31 @@ impl Conv3x3 {
             padding,
             feat_row,
             feat_col,
-            weight,
-            bias,
-            d_weight: Matrix::new(9 * in_channels, out_channels),
-            d_bias: Matrix::new(1, out_channels),
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
             pinned_memory_for_im2col: Matrix::new(100, 9 * in_channels),
             last_input_shape: (0, 0),
-            v_weight: Matrix::null(),
-            v_bias: Matrix::null(),
         }
     }
+    // build on existing, possibly shared, weight ((9 * in_channels) * out_channels) and bias
+    pub unsafe fn from_parameters(
+        in_channels: usize,
+        im_row: usize,
+        im_col: usize,
+        stride: usize,
+        padding: usize,
+        weight: Parameter,
+        bias: Parameter,
+    ) -> Self {
+        let (h, out_channels) = weight.value.shape();
+        if h != 9 * in_channels || bias.value.shape() != (1, out_channels) {
+            panic!("call from_parameters with unmatched parameter shape");
+        }
+        let mut ret = Self::new(in_channels, out_channels, im_row, im_col, stride, padding);
+        ret.weight = weight;
+        ret.bias = bias;
+        ret
+    }
     pub fn meshgrid(&self, h: usize) -> impl ParallelIterator<Item = (isize, isize, isize, isize)> {
         let padding = self.padding as isize;
         let im_row = self.im_row as isize;
@@ -148,7 +160,7 @@ impl Conv3x3 {
             (0..h).into_par_iter().for_each(|row_idx| {
                 (0..w).into_par_iter().step_by(step).for_each(|idx| {
                     let src = x.row_at(row_idx as isize);
-                    let src2 = self.bias.row_at(0);
+                    let src2 = self.bias.value.row_at(0);
                     let val = x86_64::_mm256_loadu_ps(src.add(idx));
                     let add = x86_64::_mm256_loadu_ps(src2.add(idx));
                     x86_64::_mm256_storeu_ps(src.add(idx), x86_64::_mm256_add_ps(val, add));
@@ -268,7 +280,7 @@ impl nn_trait::Layer for Conv3x3 {
         unsafe {
             self.last_input_shape = input.shape();
             self.im2col(input);
-            let res = self.pinned_memory_for_im2col.mul(&self.weight);
+            let res = self.pinned_memory_for_im2col.mul(&self.weight.value);
             self.add_bias_to_col(&res);
             self.col2im(res)
         }
@@ -284,37 +296,32 @@ impl nn_trait::Layer for Conv3x3 {
                         for j in 0..self.feat_col * self.feat_row {
                             sum += *row.add(j * self.out_channels + channel_idx);
                         }
-                        *self.d_bias.row_at(0).add(channel_idx) = sum;
                     }
+                    *self.bias.grad.row_at(0).add(channel_idx) += sum;
                 });
             let split_loss = self.split_loss(&dLoss);
-            self.d_weight = self.pinned_memory_for_im2col.T().mul(&split_loss);
-            let wt = self.weight.T();
+            self.weight
+                .grad
+                .add(&self.pinned_memory_for_im2col.T().mul(&split_loss), true);
+            let wt = self.weight.value.T();
             let ret = split_loss.mul(&wt);
             self.merge_loss(&ret)
         }
     }
-    fn trainable(&self) -> bool {
-        true
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
     }
-
-    fn parameters(
-        &mut self,
-    ) -> Option<(
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-    )> {
-        Some((
-            &mut self.weight,
-            &mut self.d_weight,
-            &mut self.v_weight,
-            &mut self.bias,
-            &mut self.d_bias,
-            &mut self.v_bias,
-        ))
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "Conv3x3",
+            &[
+                ("in_channels", self.in_channels),
+                ("out_channels", self.out_channels),
+                ("im_row", self.im_row),
+                ("im_col", self.im_col),
+                ("stride", self.stride),
+                ("padding", self.padding),
+            ],
+        )
     }
 }
@@ -0,0 +1,372 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::zeros;
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+// connectionist temporal classification over B*(T*C) logits
+// every target row holds the labels of its sample, padded with negative values
+pub struct CtcLoss {
+    pub classes: usize,
+    pub seq_len: usize,
+    pub blank: usize,
+    // valid frames of every sample for the next batch, empty for all seq_len
+    pub input_lengths: Vec<usize>,
+    // 1 for greedy decoding, prefix beam search above it
+    pub beam_width: usize,
+    pub grad: Matrix,
+}
+
+impl CtcLoss {
+    pub unsafe fn new(classes: usize, seq_len: usize, blank: usize, beam_width: usize) -> Self {
+        if blank >= classes {
+            panic!("blank is not one of the classes");
+        }
+        Self {
+            classes,
+            seq_len,
+            blank,
+            input_lengths: Vec::new(),
+            beam_width: beam_width.max(1),
+            grad: Matrix::null(),
+        }
+    }
+
+    fn input_length(&self, idx: usize) -> usize {
+        match self.input_lengths.get(idx) {
+            Some(&len) => len.min(self.seq_len),
+            None => self.seq_len,
+        }
+    }
+
+    unsafe fn check_input(&self, input: &Matrix) {
+        if input.number_of_col() != self.seq_len * self.classes {
+            panic!("call CtcLoss with unmatched input shape");
+        }
+        if !self.input_lengths.is_empty() && self.input_lengths.len() != input.number_of_row() {
+            panic!("input_lengths does not match the batch size");
+        }
+    }
+}
+
+fn log_add(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + (-(a - b).abs()).exp().ln_1p()
+}
+
+// log softmax of the first len frames of a row, len*C
+unsafe fn log_softmax_frames(row: *mut f32, len: usize, c: usize) -> Vec<f32> {
+    let mut ret = vec![0f32; len * c];
+    for t in 0..len {
+        let frame = row.add(t * c);
+        let max_val = (1..c).map(|k| *frame.add(k)).fold(*frame, f32::max);
+        let log_sum = (0..c)
+            .map(|k| (*frame.add(k) - max_val).exp())
+            .sum::<f32>()
+            .ln();
+        for k in 0..c {
+            ret[t * c + k] = *frame.add(k) - max_val - log_sum;
+        }
+    }
+    ret
+}
+
+impl nn_trait::Head for CtcLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            self.check_input(&input);
+            let h = input.number_of_row();
+            if target.number_of_row() != h {
+                panic!("call CtcLoss with unmatched target shape");
+            }
+            let c = self.classes;
+            let ret = Matrix::new(h, 1);
+            self.grad = zeros(h, self.seq_len * c);
+
+            (0..h).into_par_iter().for_each(|idx| {
+                let src_row = input.row_at(idx as isize);
+                let grad_row = self.grad.row_at(idx as isize);
+                let target_row = target.row_at(idx as isize);
+                let labels: Vec<usize> = (0..target.number_of_col())
+                    .map(|i| *target_row.add(i))
+                    .take_while(|&x| x >= 0.0)
+                    .map(|x| x as usize)
+                    .collect();
+                if labels.iter().any(|&l| l >= c || l == self.blank) {
+                    panic!("CtcLoss target holds the blank or an unknown class");
+                }
+                let len = self.input_length(idx);
+                let lp = log_softmax_frames(src_row, len, c);
+
+                // labels with a blank before, between and after them
+                let mut ext = vec![self.blank; 2 * labels.len() + 1];
+                for (i, &l) in labels.iter().enumerate() {
+                    ext[2 * i + 1] = l;
+                }
+                let s = ext.len();
+                // s - 2 can be skipped into s unless it is a blank or the same label
+                let skip = |i: usize| i >= 2 && ext[i] != self.blank && ext[i] != ext[i - 2];
+
+                let mut alpha = vec![f32::NEG_INFINITY; len * s];
+                let mut beta = vec![f32::NEG_INFINITY; len * s];
+                if len > 0 {
+                    alpha[0] = lp[ext[0]];
+                    if s > 1 {
+                        alpha[1] = lp[ext[1]];
+                    }
+                    for t in 1..len {
+                        for i in 0..s {
+                            let mut a = alpha[(t - 1) * s + i];
+                            if i >= 1 {
+                                a = log_add(a, alpha[(t - 1) * s + i - 1]);
+                            }
+                            if skip(i) {
+                                a = log_add(a, alpha[(t - 1) * s + i - 2]);
+                            }
+                            alpha[t * s + i] = a + lp[t * c + ext[i]];
+                        }
+                    }
+                    let last = (len - 1) * s;
+                    beta[last + s - 1] = lp[(len - 1) * c + ext[s - 1]];
+                    if s > 1 {
+                        beta[last + s - 2] = lp[(len - 1) * c + ext[s - 2]];
+                    }
+                    for t in (0..len - 1).rev() {
+                        for i in 0..s {
+                            let mut b = beta[(t + 1) * s + i];
+                            if i + 1 < s {
+                                b = log_add(b, beta[(t + 1) * s + i + 1]);
+                            }
+                            if i + 2 < s && skip(i + 2) {
+                                b = log_add(b, beta[(t + 1) * s + i + 2]);
+                            }
+                            beta[t * s + i] = b + lp[t * c + ext[i]];
+                        }
+                    }
+                }
+
+                let log_z = if len == 0 {
+                    f32::NEG_INFINITY
+                } else if s > 1 {
+                    log_add(alpha[(len - 1) * s + s - 1], alpha[(len - 1) * s + s - 2])
+                } else {
+                    alpha[(len - 1) * s]
+                };
+                // too few frames for the labels, such samples give no loss and no gradient
+                if log_z == f32::NEG_INFINITY {
+                    *ret.row_at(idx as isize) = 0.0;
+                    return;
+                }
+                *ret.row_at(idx as isize) = -log_z;
+
+                // d loss / d logit = p - sum of alpha * beta / p over the positions of that class / Z
+                for t in 0..len {
+                    let mut occupancy = vec![f32::NEG_INFINITY; c];
+                    for i in 0..s {
+                        occupancy[ext[i]] =
+                            log_add(occupancy[ext[i]], alpha[t * s + i] + beta[t * s + i]);
+                    }
+                    for k in 0..c {
+                        let log_p = lp[t * c + k];
+                        *grad_row.add(t * c + k) =
+                            log_p.exp() - (occupancy[k] - log_p - log_z).exp();
+                    }
+                }
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe {
+            self.check_input(input);
+            let sequences = (0..input.number_of_row())
+                .into_par_iter()
+                .map(|idx| {
+                    let len = self.input_length(idx);
+                    let lp = log_softmax_frames(input.row_at(idx as isize), len, self.classes);
+                    if self.beam_width > 1 {
+                        prefix_beam_decode(&lp, self.classes, self.blank, self.beam_width)
+                    } else {
+                        greedy_decode(&lp, self.classes, self.blank)
+                    }
+                })
+                .collect();
+            Prediction::Sequences(sequences)
+        }
+    }
+    fn kind(&self) -> &'static str {
+        "CtcLoss"
+    }
+}
+
+// best class of every frame, repeats merged and blanks dropped
+pub fn greedy_decode(log_probs: &[f32], classes: usize, blank: usize) -> Vec<usize> {
+    let mut ret = Vec::new();
+    let mut last = blank;
+    for frame in log_probs.chunks(classes) {
+        let best = (1..classes).fold(0, |a, k| if frame[a] < frame[k] { k } else { a });
+        if best != blank && best != last {
+            ret.push(best);
+        }
+        last = best;
+    }
+    ret
+}
+
+// keeps the beam_width most probable label prefixes, each scored by the log probability
+// of its paths ending in a blank and ending in its last label
+pub fn prefix_beam_decode(
+    log_probs: &[f32],
+    classes: usize,
+    blank: usize,
+    beam_width: usize,
+) -> Vec<usize> {
+    let mut beams: Vec<(Vec<usize>, f32, f32)> = vec![(Vec::new(), 0.0, f32::NEG_INFINITY)];
+    for frame in log_probs.chunks(classes) {
+        let mut next: HashMap<Vec<usize>, (f32, f32)> = HashMap::new();
+        for (prefix, p_blank, p_label) in beams.iter() {
+            let p_total = log_add(*p_blank, *p_label);
+            for (k, &p) in frame.iter().enumerate() {
+                if k == blank {
+                    let entry = next
+                        .entry(prefix.clone())
+                        .or_insert((f32::NEG_INFINITY, f32::NEG_INFINITY));
+                    entry.0 = log_add(entry.0, p_total + p);
+                    continue;
+                }
+                let mut extended = prefix.clone();
+                extended.push(k);
+                let entry = next
+                    .entry(extended)
+                    .or_insert((f32::NEG_INFINITY, f32::NEG_INFINITY));
+                if prefix.last() == Some(&k) {
+                    // a repeat only extends the prefix after a blank, otherwise it merges
+                    entry.1 = log_add(entry.1, p_blank + p);
+                    let same = next
+                        .entry(prefix.clone())
+                        .or_insert((f32::NEG_INFINITY, f32::NEG_INFINITY));
+                    same.1 = log_add(same.1, p_label + p);
+                } else {
+                    entry.1 = log_add(entry.1, p_total + p);
+                }
+            }
+        }
+        let mut candidates: Vec<(Vec<usize>, f32, f32)> = next
+            .into_iter()
+            .map(|(prefix, (b, l))| (prefix, b, l))
+            .collect();
+        // ties broken on the prefix so the result does not depend on the hash order
+        candidates.sort_by(|a, b| {
+            log_add(b.1, b.2)
+                .total_cmp(&log_add(a.1, a.2))
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        candidates.truncate(beam_width);
+        beams = candidates;
+    }
+    beams
+        .into_iter()
+        .next()
+        .map(|(prefix, _, _)| prefix)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_head, rand_mat};
+    use crate::utils::nn_trait::Head;
+
+    // labels padded with -1: [1, 1], [2, 3, 1] and []
+    unsafe fn targets() -> Matrix {
+        let ret = Matrix::new(3, 3);
+        ret.fill_(-1.0);
+        for (i, j, label) in [
+            (0, 0, 1.0),
+            (0, 1, 1.0),
+            (1, 0, 2.0),
+            (1, 1, 3.0),
+            (1, 2, 1.0),
+        ] {
+            *ret.row_at(i).add(j) = label;
+        }
+        ret
+    }
+
+    // -ln of the summed probability of every path that collapses to the labels
+    unsafe fn brute_force(
+        x: &Matrix,
+        row: usize,
+        len: usize,
+        classes: usize,
+        labels: &[usize],
+    ) -> f64 {
+        let prob = |t: usize, k: usize| {
+            let logit = |k: usize| (x.at(row as isize, (t * classes + k) as isize) as f64).exp();
+            logit(k) / (0..classes).map(logit).sum::<f64>()
+        };
+        let mut total = 0f64;
+        for path in 0..classes.pow(len as u32) {
+            let (mut rest, mut p, mut last) = (path, 1f64, 0);
+            let mut collapsed = Vec::new();
+            for t in 0..len {
+                let k = rest % classes;
+                rest /= classes;
+                p *= prob(t, k);
+                if k != 0 && k != last {
+                    collapsed.push(k);
+                }
+                last = k;
+            }
+            if collapsed == labels {
+                total += p;
+            }
+        }
+        -total.ln()
+    }
+
+    #[test]
+    fn loss_sums_every_alignment() {
+        unsafe {
+            let mut seed = 9u32;
+            let (seq_len, classes) = (5usize, 4usize);
+            let x = rand_mat(3, seq_len * classes, &mut seed);
+            x.mul_with_numeric(3.0, true);
+            let mut head = CtcLoss::new(classes, seq_len, 0, 1);
+            head.input_lengths = vec![5, 4, 3];
+            let loss = head.forward(x.clone(), targets());
+            let labels: [&[usize]; 3] = [&[1, 1], &[2, 3, 1], &[]];
+            for (row, labels) in labels.iter().enumerate() {
+                let expected = brute_force(&x, row, head.input_lengths[row], classes, labels);
+                assert!((expected - loss.at(row as isize, 0) as f64).abs() < 1e-3);
+            }
+            assert!(check_head(&mut head, &x, &targets()) < 2e-2);
+
+            // three labels can not fit in two frames
+            head.input_lengths = vec![5, 2, 3];
+            let loss = head.forward(x, targets());
+            assert_eq!(loss.at(1, 0), 0.0);
+        }
+    }
+
+    #[test]
+    fn beam_search_beats_greedy() {
+        let probabilities = [[0.6f32, 0.4, 0.0], [0.6, 0.4, 0.0]];
+        let log_probs: Vec<f32> = probabilities
+            .iter()
+            .flat_map(|row| row.iter().map(|p| p.max(1e-9).ln()))
+            .collect();
+        assert_eq!(greedy_decode(&log_probs, 3, 0), Vec::<usize>::new());
+        assert_eq!(prefix_beam_decode(&log_probs, 3, 0, 8), vec![1]);
+    }
+}
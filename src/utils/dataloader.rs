@@ -12,6 +12,7 @@ where
 {
     dataset: &'a T,
     batch_size: usize,
+    seed: u32,
     count: usize,
     order: Vec<usize>,
 }
@@ -26,10 +27,24 @@ where
         Self {
             dataset,
             batch_size,
+            seed,
             count: 0,
             order,
         }
     }
+    // the same order as new with this seed, starting after the first position samples
+    pub fn resume(dataset: &'a T, batch_size: usize, seed: u32, position: usize) -> Self {
+        let mut ret = Self::new(dataset, batch_size, seed);
+        ret.count = position.min(dataset.len());
+        ret
+    }
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+    // samples handed out so far
+    pub fn position(&self) -> usize {
+        self.count
+    }
 
     pub unsafe fn fetch_batch(&self, start: usize, len: usize) -> (Matrix, Matrix) {
         let image = Matrix::new(len, self.dataset.dim());
@@ -0,0 +1,216 @@
+use crate::utils::head::softmax_distribution;
+use crate::utils::mat::Matrix;
+use crate::utils::multilabel::{sigmoid, threshold_sigmoid};
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
+use rayon::prelude::*;
+
+// -alpha * (1 - p_t)^gamma * ln(p_t), p_t the softmax probability of the one-hot target
+pub struct SoftMaxFocalLoss {
+    pub gamma: f32,
+    pub alpha: f32,
+    pub grad: Matrix,
+}
+
+impl SoftMaxFocalLoss {
+    pub unsafe fn new(gamma: f32, alpha: f32) -> Self {
+        Self {
+            gamma,
+            alpha,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for SoftMaxFocalLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            let ret = Matrix::new(h, 1);
+            self.grad = Matrix::new(h, w);
+            let gamma = self.gamma;
+            let alpha = self.alpha;
+
+            (0..h).into_par_iter().for_each(|idx| {
+                let src_row = input.row_at(idx as isize);
+                let grad_row = self.grad.row_at(idx as isize);
+                let target_row = target.row_at(idx as isize);
+                let label = (1..w)
+                    .map(|i| (i, *target_row.add(i)))
+                    .fold((0, *target_row), |a, b| if a.1 < b.1 { b } else { a })
+                    .0;
+
+                let max_val = (1..w).map(|i| *src_row.add(i)).fold(*src_row, f32::max);
+                // taken from the logit, the exp of a label far below the max underflows to 0
+                let x_label = *src_row.add(label);
+                let mut sum = 0.0;
+                for i in 0..w {
+                    let now = (*src_row.add(i) - max_val).exp();
+                    *src_row.add(i) = now;
+                    sum += now;
+                }
+                let log_p = x_label - max_val - sum.ln();
+                for i in 0..w {
+                    *src_row.add(i) /= sum;
+                }
+                let p = *src_row.add(label);
+                // 1 - p_t summed from the other classes, exact when p_t rounds to 1
+                let q = (0..w)
+                    .filter(|&i| i != label)
+                    .map(|i| *src_row.add(i))
+                    .sum::<f32>();
+
+                // ln(p_t) / (1 - p_t) tends to -1 as p_t tends to 1
+                let ratio = if q < 1e-6 { -1.0 } else { log_p / q };
+                let modulator = q.powf(gamma);
+                let g = alpha * (gamma * p * modulator * ratio - modulator);
+                for i in 0..w {
+                    let onehot = if i == label { 1.0 } else { 0.0 };
+                    *grad_row.add(i) = g * (onehot - *src_row.add(i));
+                }
+                *ret.row_at(idx as isize) = -alpha * modulator * log_p;
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe { softmax_distribution(input) }
+    }
+    fn kind(&self) -> &'static str {
+        "SoftMaxFocalLoss"
+    }
+}
+
+// per class -alpha_t * (1 - p_t)^gamma * ln(p_t), summed over the classes
+// alpha_t is alpha for positives and 1 - alpha for negatives
+pub struct SigmoidFocalLoss {
+    pub gamma: f32,
+    pub alpha: f32,
+    pub threshold: f32,
+    pub grad: Matrix,
+}
+
+impl SigmoidFocalLoss {
+    pub unsafe fn new(gamma: f32, alpha: f32, threshold: f32) -> Self {
+        Self {
+            gamma,
+            alpha,
+            threshold,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for SigmoidFocalLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            let ret = Matrix::new(h, 1);
+            self.grad = Matrix::new(h, w);
+            let gamma = self.gamma;
+            let alpha = self.alpha;
+
+            (0..h).into_par_iter().for_each(|idx| {
+                let src_row = input.row_at(idx as isize);
+                let grad_row = self.grad.row_at(idx as isize);
+                let target_row = target.row_at(idx as isize);
+                let mut loss = 0f32;
+                for i in 0..w {
+                    let positive = *target_row.add(i) > 0.5;
+                    // p_t = sigmoid(u) with u = x for positives and -x for negatives
+                    let (u, sign, alpha_t) = if positive {
+                        (*src_row.add(i), 1.0, alpha)
+                    } else {
+                        (-*src_row.add(i), -1.0, 1.0 - alpha)
+                    };
+                    let p = sigmoid(u);
+                    let q = sigmoid(-u);
+                    let log_p = -((-u.abs()).exp().ln_1p() + (-u).max(0.0));
+                    let modulator = q.powf(gamma);
+                    loss -= alpha_t * modulator * log_p;
+                    let g = alpha_t * (gamma * modulator * p * log_p - modulator * q);
+                    *grad_row.add(i) = sign * g;
+                }
+                *ret.row_at(idx as isize) = loss;
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe { threshold_sigmoid(input, self.threshold) }
+    }
+    fn kind(&self) -> &'static str {
+        "SigmoidFocalLoss"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_head, onehot, rand_mat};
+    use crate::utils::head::SoftMaxCrossEntropy;
+    use crate::utils::multilabel::SigmoidBinaryCrossEntropy;
+    use crate::utils::nn_trait::Head;
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 29u32;
+            let x = rand_mat(4, 5, &mut seed)
+                .mul_with_numeric(3.0, false)
+                .unwrap();
+            let target = onehot(&[0, 2, 4, 1], 5);
+            for gamma in [0.0f32, 0.5, 2.0] {
+                let mut head = SoftMaxFocalLoss::new(gamma, 0.25);
+                assert!(check_head(&mut head, &x, &target) < 1e-2);
+                let mut head = SigmoidFocalLoss::new(gamma, 0.25, 0.5);
+                assert!(check_head(&mut head, &x, &target) < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_gamma_is_cross_entropy() {
+        unsafe {
+            let mut seed = 29u32;
+            let x = rand_mat(4, 5, &mut seed);
+            let target = onehot(&[0, 2, 4, 1], 5);
+            let focal = SoftMaxFocalLoss::new(0.0, 1.0).forward(x.clone(), target.clone());
+            let ce = SoftMaxCrossEntropy::new().forward(x.clone(), target.clone());
+            for i in 0..4 {
+                assert!((focal.at(i, 0) - ce.at(i, 0)).abs() < 1e-4);
+            }
+            // alpha 0.5 halves both terms
+            let focal = SigmoidFocalLoss::new(0.0, 0.5, 0.5).forward(x.clone(), target.clone());
+            let bce = SigmoidBinaryCrossEntropy::new(vec![], 0.5).forward(x, target);
+            for i in 0..4 {
+                assert!((2.0 * focal.at(i, 0) - bce.at(i, 0)).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn label_far_below_the_max_stays_finite() {
+        unsafe {
+            let x = Matrix::new(1, 3);
+            x.fill_(0.0);
+            *x.row_at(0) = 200.0;
+            let target = onehot(&[1], 3);
+            let mut head = SoftMaxFocalLoss::new(2.0, 0.25);
+            let loss = head.forward(x, target);
+            let grad = head.backward(loss.clone());
+            // p_t is exp(-200), so the loss is alpha * 200
+            assert!((loss.at(0, 0) - 50.0).abs() < 1e-3);
+            for j in 0..3 {
+                assert!(grad.at(0, j).is_finite());
+            }
+            assert!((grad.at(0, 1) + 0.25).abs() < 1e-4);
+        }
+    }
+}
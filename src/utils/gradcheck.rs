@@ -0,0 +1,101 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::rand_next;
+use crate::utils::nn_trait::{Head, Layer};
+
+// finite-difference checks of backward for the tests of layers and heads
+
+const EPS: f32 = 1e-2;
+
+// uniform in [-1, 1)
+pub unsafe fn rand_mat(h: usize, w: usize, seed: &mut u32) -> Matrix {
+    let ret = Matrix::new(h, w);
+    ret.fill_(0.0);
+    for i in 0..h {
+        for j in 0..w {
+            *ret.row_at(i as isize).add(j) = (rand_next(seed) % 2000) as f32 / 1000.0 - 1.0;
+        }
+    }
+    ret
+}
+
+pub unsafe fn onehot(labels: &[usize], classes: usize) -> Matrix {
+    let ret = Matrix::new(labels.len(), classes);
+    ret.fill_(0.0);
+    for (i, &label) in labels.iter().enumerate() {
+        *ret.row_at(i as isize).add(label) = 1.0;
+    }
+    ret
+}
+
+pub fn rel(a: f64, b: f64) -> f64 {
+    (a - b).abs() / (a.abs() + b.abs()).max(1e-3)
+}
+
+unsafe fn dot(a: &Matrix, b: &Matrix) -> f64 {
+    let (h, w) = a.shape();
+    let mut ret = 0f64;
+    for i in 0..h {
+        for j in 0..w {
+            ret += (a.at(i as isize, j as isize) * b.at(i as isize, j as isize)) as f64;
+        }
+    }
+    ret
+}
+
+// the worst relative error between grad and the central difference of f over every element of x
+pub unsafe fn check_grad(x: &Matrix, grad: &Matrix, mut f: impl FnMut() -> f64) -> f64 {
+    let (h, w) = x.shape();
+    let mut worst = 0f64;
+    for i in 0..h {
+        for j in 0..w {
+            let p = x.row_at(i as isize).add(j);
+            let old = *p;
+            *p = old + EPS;
+            let plus = f();
+            *p = old - EPS;
+            let minus = f();
+            *p = old;
+            let numeric = (plus - minus) / (2.0 * EPS as f64);
+            worst = worst.max(rel(numeric, grad.at(i as isize, j as isize) as f64));
+        }
+    }
+    worst
+}
+
+// backward of a random projection of the output, checked for the input and every parameter
+pub unsafe fn check_layer(layer: &mut dyn Layer, x: &Matrix) -> f64 {
+    let mut seed = 7u32;
+    let (h, w) = layer.forward(x.clone()).shape();
+    let r = rand_mat(h, w, &mut seed);
+    for parameter in layer.parameters() {
+        parameter.grad.fill_(0.0);
+    }
+    let dx = layer.backward(r.clone());
+    // the values are changed in place through these while the layer runs
+    let parameters: Vec<(*const Matrix, Matrix)> = layer
+        .parameters()
+        .into_iter()
+        .map(|parameter| (&parameter.value as *const Matrix, parameter.grad.clone()))
+        .collect();
+    let mut worst = check_grad(x, &dx, || dot(&layer.forward(x.clone()), &r));
+    for (value, grad) in parameters.iter() {
+        worst = worst.max(check_grad(&**value, grad, || {
+            dot(&layer.forward(x.clone()), &r)
+        }));
+    }
+    worst
+}
+
+// the summed loss over the batch
+pub unsafe fn total_loss(head: &mut dyn Head, x: &Matrix, target: &Matrix) -> f64 {
+    let loss = head.forward(x.clone(), target.clone());
+    (0..loss.number_of_row())
+        .map(|i| loss.at(i as isize, 0) as f64)
+        .sum()
+}
+
+pub unsafe fn check_head(head: &mut dyn Head, x: &Matrix, target: &Matrix) -> f64 {
+    let loss = head.forward(x.clone(), target.clone());
+    let dx = head.backward(loss);
+    check_grad(x, &dx, || total_loss(head, x, target))
+}
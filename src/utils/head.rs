@@ -1,15 +1,32 @@
 use crate::utils::mat::Matrix;
 use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
 use rayon::prelude::*;
 
 pub struct SoftMaxCrossEntropy {
     pub grad: Matrix,
+    // the one-hot target becomes (1 - eps) * target + eps / classes
+    pub label_smoothing: f32,
+    // weight of every class, empty for all ones
+    pub class_weight: Vec<f32>,
+    // samples of this class give no loss and no gradient
+    pub ignore_index: Option<usize>,
 }
 
 impl SoftMaxCrossEntropy {
     pub unsafe fn new() -> Self {
+        Self::with_options(0.0, Vec::new(), None)
+    }
+    pub unsafe fn with_options(
+        label_smoothing: f32,
+        class_weight: Vec<f32>,
+        ignore_index: Option<usize>,
+    ) -> Self {
         Self {
             grad: Matrix::null(),
+            label_smoothing,
+            class_weight,
+            ignore_index,
         }
     }
 }
@@ -18,6 +35,9 @@ impl nn_trait::Head for SoftMaxCrossEntropy {
     fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
         unsafe {
             let (h, w) = input.shape();
+            if !self.class_weight.is_empty() && self.class_weight.len() != w {
+                panic!("class_weight does not match the number of classes");
+            }
             let ret = Matrix::new(h, 1);
             self.grad = Matrix::new(h, w);
 
@@ -41,14 +61,31 @@ impl nn_trait::Head for SoftMaxCrossEntropy {
                 for i in 0..w {
                     *src_row.add(i) /= sum;
                 }
-                for i in 0..w {
-                    *grad_row.add(i) = *src_row.add(i) - *target_row.add(i);
+                let label = (1..w)
+                    .map(|i| (i, *target_row.add(i)))
+                    .fold((0, *target_row), |a, b| if a.1 < b.1 { b } else { a })
+                    .0;
+                if self.ignore_index == Some(label) {
+                    for i in 0..w {
+                        *grad_row.add(i) = 0.0;
+                    }
+                    *ret.row_at(idx as isize).offset(0) = 0.0;
+                    return;
                 }
 
+                // loss = -sum(w_i * q_i * ln(p_i)), its gradient is p_i * sum(w * q) - w_i * q_i
+                let smoothing = self.label_smoothing;
+                let weighted_target = |i: usize| {
+                    let target = (1.0 - smoothing) * *target_row.add(i) + smoothing / w as f32;
+                    target * self.class_weight.get(i).copied().unwrap_or(1.0)
+                };
+                let total = (0..w).map(weighted_target).sum::<f32>();
+
                 let mut loss = 0f32;
                 for i in 0..w {
                     let mut pred = *src_row.add(i);
-                    let target = *target_row.add(i);
+                    let target = weighted_target(i);
+                    *grad_row.add(i) = pred * total - target;
                     if pred < 1e-7 {
                         pred = 1e-7;
                     }
@@ -63,20 +100,145 @@ impl nn_trait::Head for SoftMaxCrossEntropy {
         self.grad.clone()
     }
 
-    fn eval_forward(&self, input: Matrix) -> Vec<usize> {
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe { softmax_distribution(input) }
+    }
+    fn kind(&self) -> &'static str {
+        "SoftMaxCrossEntropy"
+    }
+}
+
+pub unsafe fn arg_max(input: &Matrix) -> Vec<usize> {
+    let (h, w) = input.shape();
+    let mut ret = vec![0; h];
+    let ptr = ret.as_mut_ptr();
+    (0..h).into_iter().for_each(|idx| {
+        let src_row = input.row_at(idx as isize);
+        let arg_max = (1..w)
+            .map(|i| (i, *src_row.add(i)))
+            .fold((0, *src_row), |a, b| if a.1 < b.1 { b } else { a })
+            .0;
+        *ptr.add(idx) = arg_max;
+    });
+    ret
+}
+
+// the k largest entries of every row as (column, value), largest first
+pub unsafe fn top_k(input: &Matrix, k: usize) -> Vec<Vec<(usize, f32)>> {
+    let (h, w) = input.shape();
+    (0..h)
+        .into_par_iter()
+        .map(|idx| {
+            let src_row = input.row_at(idx as isize);
+            let mut row: Vec<(usize, f32)> = (0..w).map(|i| (i, *src_row.add(i))).collect();
+            // stable, ties keep the lower index first
+            row.sort_by(|a, b| b.1.total_cmp(&a.1));
+            row.truncate(k);
+            row
+        })
+        .collect()
+}
+
+// softmax and log softmax of the logits into new matrices, the input is left untouched
+pub unsafe fn softmax_distribution(input: &Matrix) -> Prediction {
+    let (h, w) = input.shape();
+    let probabilities = Matrix::new(h, w);
+    let log_probabilities = Matrix::new(h, w);
+    (0..h).into_par_iter().for_each(|idx| {
+        let src_row = input.row_at(idx as isize);
+        let prob_row = probabilities.row_at(idx as isize);
+        let log_row = log_probabilities.row_at(idx as isize);
+        let max_val = (1..w).map(|i| *src_row.add(i)).fold(*src_row, f32::max);
+        let log_sum = (0..w)
+            .map(|i| (*src_row.add(i) - max_val).exp())
+            .sum::<f32>()
+            .ln();
+        for i in 0..w {
+            let log_prob = *src_row.add(i) - max_val - log_sum;
+            *log_row.add(i) = log_prob;
+            *prob_row.add(i) = log_prob.exp();
+        }
+    });
+    Prediction::Distribution {
+        probabilities,
+        log_probabilities,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_head, onehot, rand_mat};
+    use crate::utils::nn_trait::Head;
+
+    #[test]
+    fn backward_matches_finite_differences() {
         unsafe {
-            let (h, w) = input.shape();
-            let mut ret = vec![0; h];
-            let ptr = ret.as_mut_ptr();
-            (0..h).into_iter().for_each(|idx| {
-                let src_row = input.row_at(idx as isize);
-                let arg_max = (1..w)
-                    .map(|i| (i, *src_row.add(i)))
-                    .fold((0, *src_row), |a, b| if a.1 < b.1 { b } else { a })
-                    .0;
-                *ptr.add(idx) = arg_max;
-            });
-            ret
+            let mut seed = 23u32;
+            let x = rand_mat(4, 5, &mut seed)
+                .mul_with_numeric(3.0, false)
+                .unwrap();
+            let target = onehot(&[0, 2, 4, 1], 5);
+            assert!(check_head(&mut SoftMaxCrossEntropy::new(), &x, &target) < 1e-2);
+            let mut head =
+                SoftMaxCrossEntropy::with_options(0.1, vec![1.0, 2.0, 0.5, 3.0, 1.0], None);
+            assert!(check_head(&mut head, &x, &target) < 1e-2);
+            let mut head = SoftMaxCrossEntropy::with_options(0.1, vec![], Some(2));
+            assert!(check_head(&mut head, &x, &target) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn ignored_samples_give_nothing() {
+        unsafe {
+            let mut seed = 23u32;
+            let x = rand_mat(4, 5, &mut seed);
+            let mut head = SoftMaxCrossEntropy::with_options(0.1, vec![], Some(2));
+            let loss = head.forward(x, onehot(&[0, 2, 4, 1], 5));
+            let grad = head.backward(loss.clone());
+            assert_eq!(loss.at(1, 0), 0.0);
+            assert!(loss.at(0, 0) > 0.0);
+            for j in 0..5 {
+                assert_eq!(grad.at(1, j), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn top_k_and_distribution() {
+        unsafe {
+            let x = Matrix::new(2, 4);
+            for (j, v) in [1.0, 3.0, 1.0, 2.0].iter().enumerate() {
+                *x.row_at(0).add(j) = *v;
+            }
+            for (j, v) in [-200.0, 0.0, 50.0, 0.0].iter().enumerate() {
+                *x.row_at(1).add(j) = *v;
+            }
+            let best = top_k(&x, 3);
+            assert_eq!(best[0], vec![(1, 3.0), (3, 2.0), (0, 1.0)]);
+            // the tie between 1 and 3 keeps the lower index first
+            assert_eq!(best[1], vec![(2, 50.0), (1, 0.0), (3, 0.0)]);
+            // more than the classes gives every class
+            assert_eq!(top_k(&x, 9)[0].len(), 4);
+
+            let prediction = SoftMaxCrossEntropy::new().eval_forward(&x);
+            assert_eq!(prediction.top_k(1)[1][0].0, 2);
+            let (probabilities, log_probabilities) = match prediction {
+                Prediction::Distribution {
+                    probabilities,
+                    log_probabilities,
+                } => (probabilities, log_probabilities),
+                _ => panic!("expected a distribution"),
+            };
+            for i in 0..2 {
+                let sum: f32 = (0..4).map(|j| probabilities.at(i, j)).sum();
+                assert!((sum - 1.0).abs() < 1e-5);
+            }
+            assert!((log_probabilities.at(0, 1) - probabilities.at(0, 1).ln()).abs() < 1e-5);
+            // finite far below the max, where the probability underflows
+            assert!((log_probabilities.at(1, 0) + 250.0).abs() < 1e-3);
+            // the logits are left untouched
+            assert_eq!(x.at(0, 1), 3.0);
         }
     }
 }
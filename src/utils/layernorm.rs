@@ -0,0 +1,140 @@
+use crate::utils::mat::Matrix;
+use crate::utils::nn_trait;
+use crate::utils::parameter::Parameter;
+use rayon::prelude::*;
+
+// normalizes every D wide token of a B*(TD) input
+pub struct LayerNorm {
+    pub d_model: usize,
+    pub eps: f32,
+
+    // gamma and beta, 1*D
+    pub weight: Parameter,
+    pub bias: Parameter,
+
+    last_norm: Matrix,
+    // 1 / std of every token, B*T
+    last_inv_std: Matrix,
+}
+
+impl LayerNorm {
+    pub unsafe fn new(d_model: usize) -> Self {
+        let weight = Matrix::new(1, d_model);
+        let bias = Matrix::new(1, d_model);
+        weight.fill_(1.0);
+        bias.fill_(0.0);
+        Self {
+            d_model,
+            eps: 1e-5,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            last_norm: Matrix::null(),
+            last_inv_std: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Layer for LayerNorm {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            if !w.is_multiple_of(self.d_model) {
+                panic!("call LayerNorm with unmatched input shape");
+            }
+            let d = self.d_model;
+            let t = w / d;
+            let eps = self.eps;
+            self.last_norm = Matrix::new(h, w);
+            self.last_inv_std = Matrix::new(h, t);
+            (0..h).into_par_iter().for_each(|idx| {
+                let gamma = self.weight.value.row_at(0);
+                let beta = self.bias.value.row_at(0);
+                let src = input.row_at(idx as isize);
+                let norm = self.last_norm.row_at(idx as isize);
+                let inv_std = self.last_inv_std.row_at(idx as isize);
+                for k in 0..t {
+                    let x = src.add(k * d);
+                    let y = norm.add(k * d);
+                    let mean = (0..d).map(|i| *x.add(i)).sum::<f32>() / d as f32;
+                    let var = (0..d)
+                        .map(|i| (*x.add(i) - mean) * (*x.add(i) - mean))
+                        .sum::<f32>()
+                        / d as f32;
+                    let s = 1.0 / (var + eps).sqrt();
+                    *inv_std.add(k) = s;
+                    for i in 0..d {
+                        let val = (*x.add(i) - mean) * s;
+                        *y.add(i) = val;
+                        *x.add(i) = val * *gamma.add(i) + *beta.add(i);
+                    }
+                }
+            });
+            input
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = dLoss.shape();
+            let d = self.d_model;
+            let t = w / d;
+            let d_gamma = self.weight.grad.row_at(0);
+            let d_beta = self.bias.grad.row_at(0);
+            for idx in 0..h {
+                let dy = dLoss.row_at(idx as isize);
+                let norm = self.last_norm.row_at(idx as isize);
+                for i in 0..w {
+                    *d_gamma.add(i % d) += *dy.add(i) * *norm.add(i);
+                    *d_beta.add(i % d) += *dy.add(i);
+                }
+            }
+            let ret = Matrix::new(h, w);
+            (0..h).into_par_iter().for_each(|idx| {
+                let gamma = self.weight.value.row_at(0);
+                let dy = dLoss.row_at(idx as isize);
+                let norm = self.last_norm.row_at(idx as isize);
+                let inv_std = self.last_inv_std.row_at(idx as isize);
+                let dst = ret.row_at(idx as isize);
+                for k in 0..t {
+                    let o = k * d;
+                    let mut sum = 0.0;
+                    let mut sum_norm = 0.0;
+                    for i in 0..d {
+                        let dn = *dy.add(o + i) * *gamma.add(i);
+                        sum += dn;
+                        sum_norm += dn * *norm.add(o + i);
+                    }
+                    let s = *inv_std.add(k) / d as f32;
+                    for i in 0..d {
+                        let dn = *dy.add(o + i) * *gamma.add(i);
+                        *dst.add(o + i) = s * (d as f32 * dn - sum - *norm.add(o + i) * sum_norm);
+                    }
+                }
+            });
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new("LayerNorm", &[("d_model", self.d_model)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_layer, rand_mat};
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let mut norm = LayerNorm::new(4);
+            norm.weight.value = rand_mat(1, 4, &mut seed);
+            norm.bias.value = rand_mat(1, 4, &mut seed);
+            let x = rand_mat(3, 12, &mut seed);
+            assert!(check_layer(&mut norm, &x) < 2e-2);
+        }
+    }
+}
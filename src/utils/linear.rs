@@ -1,35 +1,42 @@
 use crate::utils::mat::Matrix;
 use crate::utils::nn_trait;
+use crate::utils::parameter::Parameter;
 use std::arch::x86_64;
 use std::mem::size_of;
 
 pub struct LinearLayer {
     last_input: Matrix,
-    pub weight: Matrix,
-    pub d_weight: Matrix,
-    pub v_weight: Matrix,
-    pub bias: Matrix,
-    pub d_bias: Matrix,
-    pub v_bias: Matrix,
+    pub weight: Parameter,
+    pub bias: Parameter,
+    // multiply by weight^T, for a decoder tied to an encoder's weight
+    pub transpose: bool,
 }
 
 impl LinearLayer {
     pub unsafe fn new(in_channels: usize, out_channels: usize) -> Self {
         let last_input = Matrix::null();
         let weight = Matrix::new(in_channels, out_channels);
-        let d_weight = Matrix::new(in_channels, out_channels);
         let bias = Matrix::new(1, out_channels);
-        let d_bias = Matrix::new(1, out_channels);
         bias.normal_init();
         weight.normal_init();
         Self {
             last_input,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            transpose: false,
+        }
+    }
+    pub unsafe fn from_parameters(weight: Parameter, bias: Parameter, transpose: bool) -> Self {
+        let (h, w) = weight.value.shape();
+        let out_channels = if transpose { h } else { w };
+        if bias.value.shape() != (1, out_channels) {
+            panic!("call from_parameters with unmatched bias shape");
+        }
+        Self {
+            last_input: Matrix::null(),
             weight,
-            d_weight,
             bias,
-            d_bias,
-            v_weight: Matrix::null(),
-            v_bias: Matrix::null(),
+            transpose,
         }
     }
 }
@@ -37,8 +44,12 @@ impl LinearLayer {
 impl nn_trait::Layer for LinearLayer {
     fn forward(&mut self, input: Matrix) -> Matrix {
         unsafe {
-            let now = input.mul(&self.weight);
-            now.add_with_vector(&self.bias, true);
+            let now = if self.transpose {
+                input.mul(&self.weight.value.T())
+            } else {
+                input.mul(&self.weight.value)
+            };
+            now.add_with_vector(&self.bias.value, true);
             self.last_input = input;
             now
         }
@@ -47,47 +58,45 @@ impl nn_trait::Layer for LinearLayer {
         unsafe {
             let (h, w) = dLoss.shape();
 
+            // grads add up over backward calls until Network::zero_grad
             for j in (0..w).step_by(32 / size_of::<f32>()) {
-                let mut sum = x86_64::_mm256_set1_ps(0.0);
+                let mut sum = x86_64::_mm256_load_ps(self.bias.grad.row_at(0).add(j));
                 for i in 0..h {
                     let val = x86_64::_mm256_load_ps(dLoss.row_at(i as isize).add(j));
                     sum = x86_64::_mm256_add_ps(sum, val);
                 }
-                x86_64::_mm256_store_ps(self.d_bias.row_at(0).add(j), sum);
+                x86_64::_mm256_store_ps(self.bias.grad.row_at(0).add(j), sum);
+            }
+
+            if self.transpose {
+                self.weight.grad.add(&dLoss.T().mul(&self.last_input), true);
+                return dLoss.mul(&self.weight.value);
             }
 
             let xt = self.last_input.T();
 
             let xt = xt.mul(&dLoss);
 
-            self.d_weight = xt;
+            self.weight.grad.add(&xt, true);
 
-            let wt = self.weight.T();
+            let wt = self.weight.value.T();
 
             dLoss.mul(&wt)
         }
     }
-    fn trainable(&self) -> bool {
-        true
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
     }
-
-    fn parameters(
-        &mut self,
-    ) -> Option<(
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-    )> {
-        Some((
-            &mut self.weight,
-            &mut self.d_weight,
-            &mut self.v_weight,
-            &mut self.bias,
-            &mut self.d_bias,
-            &mut self.v_bias,
-        ))
+    fn config(&self) -> nn_trait::LayerConfig {
+        let (h, w) = self.weight.value.shape();
+        let (in_channels, out_channels) = if self.transpose { (w, h) } else { (h, w) };
+        nn_trait::LayerConfig::new(
+            "LinearLayer",
+            &[
+                ("in_channels", in_channels),
+                ("out_channels", out_channels),
+                ("transpose", self.transpose as usize),
+            ],
+        )
     }
 }
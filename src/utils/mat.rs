@@ -5,6 +5,7 @@ use std::arch::x86_64;
 use std::f32::consts::PI;
 use std::fmt::Formatter;
 use std::mem::size_of;
+use std::sync::Arc;
 
 struct MatrixImpl {
     ptr: *mut f32,
@@ -113,6 +114,24 @@ impl MatrixImpl {
         }
     }
 
+    // self op rhs in both modes, the in-place branch above hands the operands over swapped,
+    // which only commutative ops can ignore
+    unsafe fn ordered_ops_with_matrix<T>(
+        &self,
+        rhs: &MatrixImpl,
+        inplace: bool,
+        f: T,
+    ) -> Option<MatrixImpl>
+    where
+        T: std::ops::Fn(x86_64::__m256, x86_64::__m256) -> x86_64::__m256 + std::marker::Sync,
+    {
+        if inplace {
+            self.ops_with_matrix(rhs, inplace, |src, dst| f(dst, src), false)
+        } else {
+            self.ops_with_matrix(rhs, inplace, f, false)
+        }
+    }
+
     pub unsafe fn add_with_vector(&self, rhs: &MatrixImpl, inplace: bool) -> Option<MatrixImpl> {
         if rhs.row != 1 || self.col != rhs.col {
             panic!("call add_with_vector with unmatched matrix shape");
@@ -134,6 +153,20 @@ impl MatrixImpl {
         self.ops_with_matrix(rhs, inplace, |a, b| x86_64::_mm256_mul_ps(a, b), false)
     }
 
+    pub unsafe fn div(&self, rhs: &MatrixImpl, inplace: bool) -> Option<MatrixImpl> {
+        if self.row != rhs.row || self.col != rhs.col {
+            panic!("call div with unmatched matrix shape");
+        }
+        self.ordered_ops_with_matrix(rhs, inplace, |a, b| x86_64::_mm256_div_ps(a, b))
+    }
+
+    pub unsafe fn max(&self, rhs: &MatrixImpl, inplace: bool) -> Option<MatrixImpl> {
+        if self.row != rhs.row || self.col != rhs.col {
+            panic!("call max with unmatched matrix shape");
+        }
+        self.ordered_ops_with_matrix(rhs, inplace, |a, b| x86_64::_mm256_max_ps(a, b))
+    }
+
     unsafe fn ops_with_numeric<T>(&self, inplace: bool, rhs: f32, f: T) -> Option<MatrixImpl>
     where
         T: std::ops::Fn(x86_64::__m256, x86_64::__m256) -> x86_64::__m256 + std::marker::Sync,
@@ -171,6 +204,9 @@ impl MatrixImpl {
     pub unsafe fn mul_with_numeric(&self, rhs: f32, inplace: bool) -> Option<MatrixImpl> {
         self.ops_with_numeric(inplace, rhs, |a, b| x86_64::_mm256_mul_ps(a, b))
     }
+    pub unsafe fn sqrt(&self, inplace: bool) -> Option<MatrixImpl> {
+        self.ops_with_numeric(inplace, 0.0, |a, _| x86_64::_mm256_sqrt_ps(a))
+    }
 
     pub unsafe fn mul(&self, rhs: &MatrixImpl) -> MatrixImpl {
         if self.col != rhs.row {
@@ -306,16 +342,24 @@ impl MatrixImpl {
 }
 
 pub struct Matrix {
-    inner: Option<Box<MatrixImpl>>,
+    inner: Option<Arc<MatrixImpl>>,
 }
 
 impl Matrix {
     pub unsafe fn resize_row(&mut self, x: usize) {
-        self.inner.as_mut().unwrap().resize_row(x);
+        Arc::get_mut(self.inner.as_mut().unwrap())
+            .expect("resize a shared mat")
+            .resize_row(x);
     }
     pub unsafe fn new(n: usize, m: usize) -> Self {
         Self {
-            inner: Some(Box::new(MatrixImpl::new(n, m))),
+            inner: Some(Arc::new(MatrixImpl::new(n, m))),
+        }
+    }
+    // another handle on the same storage, the buffer is freed with the last handle
+    pub fn share(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
         }
     }
     pub unsafe fn null() -> Self {
@@ -345,9 +389,10 @@ impl Matrix {
     }
 
     pub unsafe fn free(&mut self) {
-        if let Some(inner) = self.inner.as_ref() {
-            inner.free();
-            self.inner = None;
+        if let Some(inner) = self.inner.take() {
+            if let Ok(inner) = Arc::try_unwrap(inner) {
+                inner.free();
+            }
         } else {
             panic!("free a null mat");
         }
@@ -376,7 +421,7 @@ impl Matrix {
             None
         } else {
             Some(Self {
-                inner: Some(Box::new(ret.unwrap())),
+                inner: Some(Arc::new(ret.unwrap())),
             })
         }
     }
@@ -388,7 +433,7 @@ impl Matrix {
             None
         } else {
             Some(Self {
-                inner: Some(Box::new(ret.unwrap())),
+                inner: Some(Arc::new(ret.unwrap())),
             })
         }
     }
@@ -399,7 +444,30 @@ impl Matrix {
             None
         } else {
             Some(Self {
-                inner: Some(Box::new(ret.unwrap())),
+                inner: Some(Arc::new(ret.unwrap())),
+            })
+        }
+    }
+
+    pub unsafe fn div(&self, rhs: &Matrix, inplace: bool) -> Option<Matrix> {
+        let rhs = rhs.inner.as_ref().unwrap().as_ref();
+        let ret = self.inner.as_ref().unwrap().div(rhs, inplace);
+        if inplace {
+            None
+        } else {
+            Some(Self {
+                inner: Some(Arc::new(ret.unwrap())),
+            })
+        }
+    }
+    pub unsafe fn max(&self, rhs: &Matrix, inplace: bool) -> Option<Matrix> {
+        let rhs = rhs.inner.as_ref().unwrap().as_ref();
+        let ret = self.inner.as_ref().unwrap().max(rhs, inplace);
+        if inplace {
+            None
+        } else {
+            Some(Self {
+                inner: Some(Arc::new(ret.unwrap())),
             })
         }
     }
@@ -410,7 +478,7 @@ impl Matrix {
             None
         } else {
             Some(Self {
-                inner: Some(Box::new(ret.unwrap())),
+                inner: Some(Arc::new(ret.unwrap())),
             })
         }
     }
@@ -420,7 +488,18 @@ impl Matrix {
             None
         } else {
             Some(Self {
-                inner: Some(Box::new(ret.unwrap())),
+                inner: Some(Arc::new(ret.unwrap())),
+            })
+        }
+    }
+
+    pub unsafe fn sqrt(&self, inplace: bool) -> Option<Matrix> {
+        let ret = self.inner.as_ref().unwrap().sqrt(inplace);
+        if inplace {
+            None
+        } else {
+            Some(Self {
+                inner: Some(Arc::new(ret.unwrap())),
             })
         }
     }
@@ -432,7 +511,7 @@ impl Matrix {
             .unwrap()
             .mul(rhs.inner.as_ref().unwrap().as_ref());
         Self {
-            inner: Some(Box::new(ret)),
+            inner: Some(Arc::new(ret)),
         }
     }
     pub unsafe fn fill_(&self, val: f32) {
@@ -441,7 +520,7 @@ impl Matrix {
 
     pub unsafe fn T(&self) -> Matrix {
         Self {
-            inner: Some(Box::new(self.inner.as_ref().unwrap().T())),
+            inner: Some(Arc::new(self.inner.as_ref().unwrap().T())),
         }
     }
 }
@@ -450,7 +529,7 @@ impl Clone for Matrix {
     fn clone(&self) -> Self {
         unsafe {
             Self {
-                inner: Some(Box::new(self.inner.as_ref().unwrap().deep_copy())),
+                inner: Some(Arc::new(self.inner.as_ref().unwrap().deep_copy())),
             }
         }
     }
@@ -497,5 +576,5 @@ impl std::fmt::Display for Matrix {
 }
 
 unsafe impl core::marker::Sync for MatrixImpl {}
-
-unsafe impl core::marker::Sync for Matrix {}
+// the buffer is owned, so the last handle may free it on any thread
+unsafe impl core::marker::Send for MatrixImpl {}
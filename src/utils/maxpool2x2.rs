@@ -154,7 +154,14 @@ impl nn_trait::Layer for MaxPool2x2 {
             ret
         }
     }
-    fn trainable(&self) -> bool {
-        false
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "MaxPool2x2",
+            &[
+                ("in_channels", self.in_channels),
+                ("im_row", self.im_row),
+                ("im_col", self.im_col),
+            ],
+        )
     }
 }
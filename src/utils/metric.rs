@@ -0,0 +1,427 @@
+use crate::utils::head::{arg_max, softmax_distribution};
+use crate::utils::mat::Matrix;
+use crate::utils::misc::zeros;
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
+use crate::utils::parameter::Parameter;
+use rayon::prelude::*;
+
+// euclidean distance between every two rows, h*h row major
+unsafe fn pairwise_distance(input: &Matrix) -> Vec<f32> {
+    let (h, w) = input.shape();
+    (0..h * h)
+        .into_par_iter()
+        .map(|idx| {
+            let a = input.row_at((idx / h) as isize);
+            let b = input.row_at((idx % h) as isize);
+            let sq = (0..w).map(|k| (*a.add(k) - *b.add(k)).powi(2)).sum::<f32>();
+            sq.max(1e-12).sqrt()
+        })
+        .collect()
+}
+
+unsafe fn labels_of(input: &Matrix, target: &Matrix, name: &str) -> Vec<usize> {
+    if target.number_of_row() != input.number_of_row() {
+        panic!("call {} with unmatched target shape", name);
+    }
+    arg_max(target)
+}
+
+// every pair in the batch gives 1/2 d^2 for the same label and 1/2 max(0, margin - d)^2 otherwise
+// the per-sample loss is the mean over the pairs of that sample
+pub struct ContrastiveLoss {
+    pub margin: f32,
+    pub grad: Matrix,
+}
+
+impl ContrastiveLoss {
+    pub unsafe fn new(margin: f32) -> Self {
+        Self {
+            margin,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for ContrastiveLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let labels = labels_of(&input, &target, "ContrastiveLoss");
+            let (h, w) = input.shape();
+            let dist = pairwise_distance(&input);
+            let ret = Matrix::new(h, 1);
+            self.grad = zeros(h, w);
+            let pairs = (h.max(2) - 1) as f32;
+            let margin = self.margin;
+
+            (0..h).into_par_iter().for_each(|i| {
+                let src_row = input.row_at(i as isize);
+                let grad_row = self.grad.row_at(i as isize);
+                let mut loss = 0f32;
+                for j in (0..h).filter(|&j| j != i) {
+                    let d = dist[i * h + j];
+                    // d loss / d x_i = coef * (x_i - x_j)
+                    let coef = if labels[i] == labels[j] {
+                        loss += 0.5 * d * d;
+                        1.0
+                    } else if d < margin {
+                        loss += 0.5 * (margin - d).powi(2);
+                        -(margin - d) / d
+                    } else {
+                        continue;
+                    };
+                    // the pair shows up in the loss of both samples
+                    let other = input.row_at(j as isize);
+                    for k in 0..w {
+                        *grad_row.add(k) += 2.0 * coef * (*src_row.add(k) - *other.add(k)) / pairs;
+                    }
+                }
+                *ret.row_at(i as isize) = loss / pairs;
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        Prediction::Values(input.clone())
+    }
+    fn kind(&self) -> &'static str {
+        "ContrastiveLoss"
+    }
+}
+
+// max(0, d(a, p) - d(a, n) + margin) for every anchor, with the farthest positive
+// and the closest negative in the batch, anchors without either give no loss
+pub struct TripletLoss {
+    pub margin: f32,
+    pub grad: Matrix,
+}
+
+impl TripletLoss {
+    pub unsafe fn new(margin: f32) -> Self {
+        Self {
+            margin,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for TripletLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let labels = labels_of(&input, &target, "TripletLoss");
+            let (h, w) = input.shape();
+            let dist = pairwise_distance(&input);
+            let ret = Matrix::new(h, 1);
+            self.grad = zeros(h, w);
+
+            // (positive, negative) of every anchor with a positive loss
+            let triplets: Vec<Option<(usize, usize)>> = (0..h)
+                .into_par_iter()
+                .map(|i| {
+                    let d = |j: usize| dist[i * h + j];
+                    *ret.row_at(i as isize) = 0.0;
+                    let positive = (0..h).filter(|&j| j != i && labels[j] == labels[i]).fold(
+                        None,
+                        |a: Option<usize>, j| match a {
+                            Some(a) if d(a) >= d(j) => Some(a),
+                            _ => Some(j),
+                        },
+                    );
+                    let negative = (0..h).filter(|&j| labels[j] != labels[i]).fold(
+                        None,
+                        |a: Option<usize>, j| match a {
+                            Some(a) if d(a) <= d(j) => Some(a),
+                            _ => Some(j),
+                        },
+                    );
+                    let (p, n) = (positive?, negative?);
+                    let loss = d(p) - d(n) + self.margin;
+                    if loss <= 0.0 {
+                        return None;
+                    }
+                    *ret.row_at(i as isize) = loss;
+                    Some((p, n))
+                })
+                .collect();
+
+            for (i, triplet) in triplets.into_iter().enumerate() {
+                let Some((p, n)) = triplet else {
+                    continue;
+                };
+                let (a_row, p_row, n_row) = (
+                    input.row_at(i as isize),
+                    input.row_at(p as isize),
+                    input.row_at(n as isize),
+                );
+                let (d_ap, d_an) = (dist[i * h + p], dist[i * h + n]);
+                for k in 0..w {
+                    let to_p = (*a_row.add(k) - *p_row.add(k)) / d_ap;
+                    let to_n = (*a_row.add(k) - *n_row.add(k)) / d_an;
+                    *self.grad.row_at(i as isize).add(k) += to_p - to_n;
+                    *self.grad.row_at(p as isize).add(k) -= to_p;
+                    *self.grad.row_at(n as isize).add(k) += to_n;
+                }
+            }
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        Prediction::Values(input.clone())
+    }
+    fn kind(&self) -> &'static str {
+        "TripletLoss"
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AngularMargin {
+    // ArcFace, cos(theta + m) for the target class
+    Additive,
+    // CosFace, cos(theta) - m for the target class
+    Cosine,
+}
+
+// softmax cross entropy over scale * cos(theta) between the normalised feature
+// and the normalised weight column of every class, with a margin on the target class
+pub struct AngularMarginHead {
+    pub weight: Parameter,
+    pub scale: f32,
+    pub margin: f32,
+    pub kind: AngularMargin,
+    pub grad: Matrix,
+    // d loss / d normalised center of every class and what undoes the normalisation,
+    // kept by forward for backward
+    d_centers: Matrix,
+    centers: Matrix,
+    center_norms: Vec<f32>,
+}
+
+impl AngularMarginHead {
+    pub unsafe fn new(
+        in_features: usize,
+        classes: usize,
+        scale: f32,
+        margin: f32,
+        kind: AngularMargin,
+    ) -> Self {
+        let weight = Matrix::new(in_features, classes);
+        weight.normal_init();
+        Self {
+            weight: Parameter::new("weight", weight, true),
+            scale,
+            margin,
+            kind,
+            grad: Matrix::null(),
+            d_centers: Matrix::null(),
+            centers: Matrix::null(),
+            center_norms: Vec::new(),
+        }
+    }
+    pub unsafe fn arcface(in_features: usize, classes: usize, scale: f32, margin: f32) -> Self {
+        Self::new(in_features, classes, scale, margin, AngularMargin::Additive)
+    }
+    pub unsafe fn cosface(in_features: usize, classes: usize, scale: f32, margin: f32) -> Self {
+        Self::new(in_features, classes, scale, margin, AngularMargin::Cosine)
+    }
+
+    // margin applied to the target cosine, and its derivative
+    fn target_logit(&self, cos: f32) -> (f32, f32) {
+        let m = self.margin;
+        match self.kind {
+            AngularMargin::Cosine => (cos - m, 1.0),
+            // past theta = pi - m cos(theta + m) turns back up, carry on from -1 with slope 1
+            // so the target logit keeps falling as theta grows
+            AngularMargin::Additive if cos <= (std::f32::consts::PI - m).cos() => {
+                (cos - (std::f32::consts::PI - m).cos() - 1.0, 1.0)
+            }
+            AngularMargin::Additive => {
+                let sin = (1.0 - cos * cos).max(1e-12).sqrt();
+                (cos * m.cos() - sin * m.sin(), m.cos() + cos * m.sin() / sin)
+            }
+        }
+    }
+}
+
+// every row divided by its norm, plus the norms
+unsafe fn normalize_rows(x: &Matrix) -> (Matrix, Vec<f32>) {
+    let (h, w) = x.shape();
+    let ret = Matrix::new(h, w);
+    let norms = (0..h)
+        .into_par_iter()
+        .map(|idx| {
+            let src = x.row_at(idx as isize);
+            let dst = ret.row_at(idx as isize);
+            let norm = (0..w)
+                .map(|k| *src.add(k) * *src.add(k))
+                .sum::<f32>()
+                .sqrt()
+                .max(1e-12);
+            for k in 0..w {
+                *dst.add(k) = *src.add(k) / norm;
+            }
+            norm
+        })
+        .collect();
+    (ret, norms)
+}
+
+// gradient through u = v / |v| for every row: (du - (du . u) u) / |v|
+unsafe fn normalize_rows_backward(d_unit: &Matrix, unit: &Matrix, norms: &[f32]) -> Matrix {
+    let (h, w) = d_unit.shape();
+    let ret = Matrix::new(h, w);
+    (0..h).into_par_iter().for_each(|idx| {
+        let du = d_unit.row_at(idx as isize);
+        let u = unit.row_at(idx as isize);
+        let dst = ret.row_at(idx as isize);
+        let dot = (0..w).map(|k| *du.add(k) * *u.add(k)).sum::<f32>();
+        for k in 0..w {
+            *dst.add(k) = (*du.add(k) - dot * *u.add(k)) / norms[idx];
+        }
+    });
+    ret
+}
+
+impl nn_trait::Head for AngularMarginHead {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let labels = labels_of(&input, &target, "AngularMarginHead");
+            let (h, w) = input.shape();
+            if self.weight.value.number_of_row() != w {
+                panic!("call AngularMarginHead with unmatched input shape");
+            }
+            let c = self.weight.value.number_of_col();
+            let (features, feature_norms) = normalize_rows(&input);
+            // one row per class
+            let (centers, center_norms) = normalize_rows(&self.weight.value.T());
+            let cos = features.mul(&centers.T());
+            let ret = Matrix::new(h, 1);
+            // d loss / d cos
+            let d_cos = Matrix::new(h, c);
+            let scale = self.scale;
+
+            (0..h).into_par_iter().for_each(|idx| {
+                let cos_row = cos.row_at(idx as isize);
+                let grad_row = d_cos.row_at(idx as isize);
+                let label = labels[idx];
+                let (target, d_target) = self.target_logit((*cos_row.add(label)).clamp(-1.0, 1.0));
+                let logit = |i: usize| {
+                    if i == label {
+                        scale * target
+                    } else {
+                        scale * *cos_row.add(i)
+                    }
+                };
+                let max_val = (1..c).map(logit).fold(logit(0), f32::max);
+                let sum = (0..c).map(|i| (logit(i) - max_val).exp()).sum::<f32>();
+                for i in 0..c {
+                    let p = (logit(i) - max_val).exp() / sum;
+                    *grad_row.add(i) = if i == label {
+                        scale * (p - 1.0) * d_target
+                    } else {
+                        scale * p
+                    };
+                }
+                *ret.row_at(idx as isize) = max_val + sum.ln() - logit(label);
+            });
+
+            let d_features = d_cos.mul(&centers);
+            self.d_centers = d_cos.T().mul(&features);
+            self.grad = normalize_rows_backward(&d_features, &features, &feature_norms);
+            self.centers = centers;
+            self.center_norms = center_norms;
+            ret
+        }
+    }
+    // the class centers get their gradient here, so a forward without backward,
+    // e.g. a validation loss, leaves weight.grad alone
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        self.backward_scaled(dLoss, 1.0)
+    }
+    fn backward_scaled(&mut self, _: Matrix, scale: f32) -> Matrix {
+        unsafe {
+            let d_weight =
+                normalize_rows_backward(&self.d_centers, &self.centers, &self.center_norms).T();
+            let ret = self.grad.clone();
+            if scale != 1.0 {
+                d_weight.mul_with_numeric(scale, true);
+                ret.mul_with_numeric(scale, true);
+            }
+            self.weight.grad.add(&d_weight, true);
+            ret
+        }
+    }
+    // softmax over scale * cos(theta), no margin
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe {
+            let (features, _) = normalize_rows(input);
+            let (centers, _) = normalize_rows(&self.weight.value.T());
+            let logits = features.mul(&centers.T());
+            logits.mul_with_numeric(self.scale, true);
+            softmax_distribution(&logits)
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight]
+    }
+    fn kind(&self) -> &'static str {
+        "AngularMarginHead"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_grad, check_head, onehot, rand_mat, total_loss};
+    use crate::utils::nn_trait::Head;
+
+    #[test]
+    fn pair_losses_match_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let x = rand_mat(6, 5, &mut seed);
+            let target = onehot(&[0, 1, 0, 2, 1, 0], 3);
+            for margin in [1.5f32, 100.0] {
+                assert!(check_head(&mut ContrastiveLoss::new(margin), &x, &target) < 2e-2);
+            }
+            for margin in [0.5f32, 5.0] {
+                assert!(check_head(&mut TripletLoss::new(margin), &x, &target) < 2e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn angular_margin_matches_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let x = rand_mat(6, 5, &mut seed);
+            let target = onehot(&[0, 1, 0, 2, 1, 0], 3);
+            for kind in [AngularMargin::Additive, AngularMargin::Cosine] {
+                for margin in [0.3f32, 1.2] {
+                    let mut head = AngularMarginHead::new(5, 3, 4.0, margin, kind);
+                    head.weight.value = rand_mat(5, 3, &mut seed);
+                    assert!(check_head(&mut head, &x, &target) < 5e-2);
+
+                    head.weight.grad.fill_(0.0);
+                    let loss = head.forward(x.clone(), target.clone());
+                    // only backward touches the weight grad
+                    for i in 0..5 {
+                        for j in 0..3 {
+                            assert_eq!(head.weight.grad.at(i, j), 0.0);
+                        }
+                    }
+                    head.backward(loss);
+                    let value = head.weight.value.share();
+                    let grad = head.weight.grad.clone();
+                    let worst = check_grad(&value, &grad, || total_loss(&mut head, &x, &target));
+                    assert!(worst < 2e-2);
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::utils::mat::Matrix;
+use rayon::prelude::*;
 use std::ops::Shl;
 
 pub fn check_abnormal(x: &Matrix) {
@@ -48,3 +49,86 @@ pub fn random_shuffle(x: &mut Vec<usize>, mut seed: u32) {
         }
     }
 }
+
+pub unsafe fn zeros(h: usize, w: usize) -> Matrix {
+    let ret = Matrix::new(h, w);
+    ret.fill_(0.0);
+    ret
+}
+
+// writes src into the storage of dst, handles shared with other layers see it too
+pub unsafe fn assign(dst: &Matrix, src: &Matrix) {
+    dst.fill_(0.0);
+    dst.add(src, true);
+}
+
+// copy `len` columns of every row, src[.., src_col..] => dst[.., dst_col..]
+pub unsafe fn copy_cols(src: &Matrix, src_col: usize, dst: &Matrix, dst_col: usize, len: usize) {
+    let h = src.number_of_row();
+    (0..h).into_par_iter().for_each(|idx| {
+        let from = src.row_at(idx as isize).add(src_col);
+        let to = dst.row_at(idx as isize).add(dst_col);
+        std::ptr::copy_nonoverlapping(from, to, len);
+    });
+}
+
+// sum of the squares of every element
+pub unsafe fn squared_sum(x: &Matrix) -> f32 {
+    let (h, w) = x.shape();
+    (0..h)
+        .into_par_iter()
+        .map(|idx| {
+            let row = x.row_at(idx as isize);
+            (0..w).map(|k| *row.add(k) * *row.add(k)).sum::<f32>()
+        })
+        .sum()
+}
+
+pub unsafe fn l2_norm(x: &Matrix) -> f32 {
+    squared_sum(x).sqrt()
+}
+
+// B*W => 1*W
+pub unsafe fn sum_rows(x: &Matrix) -> Matrix {
+    let (h, w) = x.shape();
+    let ret = zeros(1, w);
+    let dst = ret.row_at(0);
+    for i in 0..h {
+        let src = x.row_at(i as isize);
+        for j in 0..w {
+            *dst.add(j) += *src.add(j);
+        }
+    }
+    ret
+}
+
+pub unsafe fn slice_cols(src: &Matrix, start: usize, len: usize) -> Matrix {
+    let ret = Matrix::new(src.number_of_row(), len);
+    copy_cols(src, start, &ret, 0, len);
+    ret
+}
+
+// B*(TW) => BT*W
+pub unsafe fn split_rows(x: &Matrix, w: usize) -> Matrix {
+    let (h, total) = x.shape();
+    let t = total / w;
+    let ret = Matrix::new(h * t, w);
+    (0..h * t).into_par_iter().for_each(|idx| {
+        let from = x.row_at((idx / t) as isize).add(idx % t * w);
+        let to = ret.row_at(idx as isize);
+        std::ptr::copy_nonoverlapping(from, to, w);
+    });
+    ret
+}
+
+// BT*W => B*(TW)
+pub unsafe fn merge_rows(x: &Matrix, t: usize) -> Matrix {
+    let (h, w) = x.shape();
+    let ret = Matrix::new(h / t, t * w);
+    (0..h).into_par_iter().for_each(|idx| {
+        let from = x.row_at(idx as isize);
+        let to = ret.row_at((idx / t) as isize).add(idx % t * w);
+        std::ptr::copy_nonoverlapping(from, to, w);
+    });
+    ret
+}
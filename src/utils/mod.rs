@@ -1,15 +1,35 @@
+pub mod attention;
+pub mod average;
+pub mod checkpoint;
 pub mod cifar;
+pub mod clip;
+pub mod ctc;
 pub mod dataloader;
+pub mod focal;
+#[cfg(test)]
+pub mod gradcheck;
 pub mod mat;
+pub mod metric;
 pub mod nn_trait;
 
 pub mod head;
+pub mod layernorm;
 pub mod linear;
 pub mod mnist;
+pub mod multilabel;
 pub mod network;
+pub mod onnx;
 pub mod relu;
 
 pub mod conv3x3;
 pub mod maxpool2x2;
 pub mod misc;
 pub mod optimizer;
+pub mod parameter;
+pub mod pretrained;
+pub mod recurrent;
+pub mod regression;
+pub mod scheduler;
+pub mod trainer;
+pub mod transformer;
+pub mod upsample;
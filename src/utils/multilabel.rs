@@ -0,0 +1,135 @@
+use crate::utils::mat::Matrix;
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
+use rayon::prelude::*;
+
+// independent sigmoid per class, the per-sample loss is summed over the classes
+// log(1 + e^-|x|) keeps it finite for logits of any size
+pub struct SigmoidBinaryCrossEntropy {
+    // weight of the positive term for every class, empty for all ones
+    pub pos_weight: Vec<f32>,
+    pub threshold: f32,
+    pub grad: Matrix,
+}
+
+impl SigmoidBinaryCrossEntropy {
+    pub unsafe fn new(pos_weight: Vec<f32>, threshold: f32) -> Self {
+        Self {
+            pos_weight,
+            threshold,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+pub fn sigmoid(x: f32) -> f32 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+impl nn_trait::Head for SigmoidBinaryCrossEntropy {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            if !self.pos_weight.is_empty() && self.pos_weight.len() != w {
+                panic!("pos_weight does not match the number of classes");
+            }
+            let ret = Matrix::new(h, 1);
+            self.grad = Matrix::new(h, w);
+            (0..h).into_par_iter().for_each(|idx| {
+                let src_row = input.row_at(idx as isize);
+                let target_row = target.row_at(idx as isize);
+                let grad_row = self.grad.row_at(idx as isize);
+                let mut loss = 0f32;
+                for i in 0..w {
+                    let x = *src_row.add(i);
+                    let t = *target_row.add(i);
+                    let p = self.pos_weight.get(i).copied().unwrap_or(1.0);
+                    // -log(sigmoid(x)) = log(1 + e^-|x|) + max(-x, 0)
+                    let log_neg = (-x.abs()).exp().ln_1p() + (-x).max(0.0);
+                    loss += (1.0 - t) * x + (1.0 + (p - 1.0) * t) * log_neg;
+                    *grad_row.add(i) = sigmoid(x) * (p * t + 1.0 - t) - p * t;
+                }
+                *ret.row_at(idx as isize) = loss;
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        unsafe { threshold_sigmoid(input, self.threshold) }
+    }
+    fn kind(&self) -> &'static str {
+        "SigmoidBinaryCrossEntropy"
+    }
+}
+
+// sigmoid of the logits into a new matrix, plus the 0 / 1 decision of every class
+pub unsafe fn threshold_sigmoid(input: &Matrix, threshold: f32) -> Prediction {
+    let (h, w) = input.shape();
+    let probabilities = Matrix::new(h, w);
+    let decisions = Matrix::new(h, w);
+    (0..h).into_par_iter().for_each(|idx| {
+        let src_row = input.row_at(idx as isize);
+        let prob_row = probabilities.row_at(idx as isize);
+        let dst_row = decisions.row_at(idx as isize);
+        for i in 0..w {
+            let prob = sigmoid(*src_row.add(i));
+            *prob_row.add(i) = prob;
+            *dst_row.add(i) = if prob >= threshold { 1.0 } else { 0.0 };
+        }
+    });
+    Prediction::MultiLabel {
+        decisions,
+        probabilities,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_head, rand_mat};
+    use crate::utils::nn_trait::Head;
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 19u32;
+            let x = rand_mat(4, 5, &mut seed)
+                .mul_with_numeric(5.0, false)
+                .unwrap();
+            let target = rand_mat(4, 5, &mut seed);
+            for i in 0..4 {
+                for j in 0..5 {
+                    let p = target.row_at(i).add(j);
+                    *p = if *p > 0.0 { 1.0 } else { 0.0 };
+                }
+            }
+            let mut head = SigmoidBinaryCrossEntropy::new(vec![], 0.5);
+            assert!(check_head(&mut head, &x, &target) < 1e-2);
+            let mut head = SigmoidBinaryCrossEntropy::new(vec![1.0, 2.0, 0.5, 3.0, 1.0], 0.5);
+            assert!(check_head(&mut head, &x, &target) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn large_logits_stay_finite() {
+        unsafe {
+            let x = Matrix::new(1, 2);
+            *x.row_at(0) = 200.0;
+            *x.row_at(0).add(1) = -200.0;
+            let target = Matrix::new(1, 2);
+            *target.row_at(0) = 0.0;
+            *target.row_at(0).add(1) = 1.0;
+            let loss = SigmoidBinaryCrossEntropy::new(vec![], 0.5).forward(x, target);
+            assert!((loss.at(0, 0) - 400.0).abs() < 1e-2);
+        }
+    }
+}
@@ -1,15 +1,50 @@
+use crate::utils::checkpoint::{invalid, Progress, Reader, Stateful, Writer};
+use crate::utils::clip::{clip_gradients, GradientClip};
 use crate::utils::mat::Matrix;
-use crate::utils::nn_trait::{Head, Layer, Optimizer};
+use crate::utils::misc::assign;
+use crate::utils::nn_trait::{Head, Layer, LayerConfig, Optimizer, Prediction};
+use crate::utils::onnx;
+use crate::utils::parameter::{GroupOptions, Parameter, ParameterSelector};
+use crate::utils::pretrained::{load_tensors, Tensor};
+use std::collections::HashMap;
+use std::io::Result;
+
+const MAGIC: &[u8; 4] = b"TNNW";
+const TRAINING_MAGIC: &[u8; 4] = b"TNTS";
 
 pub struct Network {
     layers: Vec<Box<dyn Layer>>,
     pub head: Box<dyn Head>,
     pub opt: Box<dyn Optimizer>,
+    pub clip: GradientClip,
+}
+
+// borrows only the layers and the head, so the optimizer can be used alongside
+fn unique_parameters<'a>(
+    layers: &'a mut [Box<dyn Layer>],
+    head: &'a mut Box<dyn Head>,
+) -> Vec<&'a mut Parameter> {
+    let mut ret: Vec<&mut Parameter> = Vec::new();
+    for parameter in layers
+        .iter_mut()
+        .flat_map(|layer| layer.parameters())
+        .chain(head.parameters())
+    {
+        if ret.iter().all(|p| p.id() != parameter.id()) {
+            ret.push(parameter);
+        }
+    }
+    ret
 }
 
 impl Network {
     pub fn new(layers: Vec<Box<dyn Layer>>, head: Box<dyn Head>, opt: Box<dyn Optimizer>) -> Self {
-        Self { layers, head, opt }
+        Self {
+            layers,
+            head,
+            opt,
+            clip: GradientClip::Off,
+        }
     }
     pub fn forward(&mut self, mut x: Matrix) -> Matrix {
         for layer in self.layers.iter_mut() {
@@ -22,24 +57,642 @@ impl Network {
         self.head.forward(pred, target)
     }
 
-    pub unsafe fn get_result(&self, pred: Matrix) -> Vec<usize> {
+    // the logits are left untouched
+    pub unsafe fn get_result(&self, pred: &Matrix) -> Prediction {
         self.head.eval_forward(pred)
     }
 
     pub fn backward(&mut self, x: Matrix) {
-        let mut x = self.head.backward(x);
+        self.backward_scaled(x, 1.0);
+    }
+    // the loss gradient times scale goes back through the layers, adding to their grads
+    // heads give the gradient of the loss summed over the rows, so micro batches that add up
+    // to a batch need no scaling, 1 / n keeps the step size of a single micro batch
+    pub fn backward_scaled(&mut self, x: Matrix, scale: f32) {
+        let mut x = self.head.backward_scaled(x, scale);
         for layer in self.layers.iter_mut().rev() {
             x = layer.backward(x);
         }
     }
-    pub fn update_parameters(&mut self) {
-        for layer in self.layers.iter_mut() {
-            if layer.trainable() {
-                let (weight, d_weight, v_weight, bias, d_bias, v_bias) =
-                    layer.parameters().unwrap();
-                self.opt
-                    .step(weight, d_weight, v_weight, bias, d_bias, v_bias);
+    // forward, loss and scaled backward of one micro batch, returns its per-sample loss
+    pub unsafe fn accumulate(&mut self, input: Matrix, target: Matrix, scale: f32) -> Matrix {
+        let pred = self.forward(input);
+        let loss = self.calc_loss(pred, target);
+        self.backward_scaled(loss.clone(), scale);
+        loss
+    }
+    // grads add up over backward calls, update_parameters clears them after the step
+    // call this before the first micro batch if anything ran backward since the last update
+    pub fn zero_grad(&mut self) {
+        for parameter in self
+            .layers
+            .iter_mut()
+            .flat_map(|layer| layer.parameters())
+            .chain(self.head.parameters())
+        {
+            unsafe {
+                parameter.grad.fill_(0.0);
+            }
+        }
+    }
+    // shared parameters are listed once
+    pub fn parameters(&mut self) -> Vec<&mut Parameter> {
+        unique_parameters(&mut self.layers, &mut self.head)
+    }
+    // gives every selected parameter these hyperparameters, later calls override earlier ones
+    // returns how many parameters were selected
+    pub fn set_group(&mut self, selector: &ParameterSelector, options: GroupOptions) -> usize {
+        let head = self.layers.len();
+        let mut count = 0;
+        for (index, parameter) in self
+            .layers
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(index, layer)| layer.parameters().into_iter().map(move |p| (index, p)))
+            .chain(self.head.parameters().into_iter().map(|p| (head, p)))
+        {
+            let selected = match selector {
+                ParameterSelector::Layers(range) => range.contains(&index),
+                ParameterSelector::NameContains(name) => parameter.name.contains(name.as_str()),
+            };
+            if selected {
+                parameter.group = options;
+                count += 1;
+            }
+        }
+        count
+    }
+    // returns the global grad norm from before clipping, the grads are zero afterwards
+    pub fn update_parameters(&mut self) -> f32 {
+        let mut parameters: Vec<&mut Parameter> = Vec::new();
+        for parameter in self
+            .layers
+            .iter_mut()
+            .flat_map(|layer| layer.parameters())
+            .chain(self.head.parameters())
+        {
+            if !parameter.requires_grad {
+                continue;
+            }
+            match parameters.iter_mut().find(|p| p.id() == parameter.id()) {
+                Some(first) => unsafe {
+                    first.grad.add(&parameter.grad, true);
+                },
+                None => parameters.push(parameter),
+            }
+        }
+        let norm = unsafe { clip_gradients(&parameters, self.clip) };
+        self.opt.step(parameters);
+        self.zero_grad();
+        norm
+    }
+
+    // the config of every layer and the kind of the head, then name and value of every
+    // parameter, shared ones once
+    pub unsafe fn write_weights(&mut self, w: &mut Writer) {
+        w.put_u32(self.layers.len() as u32);
+        for layer in self.layers.iter() {
+            let config = layer.config();
+            w.put_str(&config.kind);
+            w.put_u32(config.hyperparameters.len() as u32);
+            for (name, value) in config.hyperparameters.iter() {
+                w.put_str(name);
+                w.put_u64(*value as u64);
+            }
+        }
+        w.put_str(self.head.kind());
+        let parameters = self.parameters();
+        w.put_u32(parameters.len() as u32);
+        for parameter in parameters {
+            w.put_str(&parameter.name);
+            w.put_matrix(&parameter.value);
+        }
+    }
+    // checks the section against this model, returns the values in parameter order
+    pub unsafe fn read_weights(&mut self, r: &mut Reader) -> Result<Vec<Matrix>> {
+        let layers = r.get_u32()? as usize;
+        if layers != self.layers.len() {
+            return Err(invalid(format!(
+                "checkpoint has {} layers, the model {}",
+                layers,
+                self.layers.len()
+            )));
+        }
+        for (idx, layer) in self.layers.iter().enumerate() {
+            let kind = r.get_str()?;
+            let mut hyperparameters = Vec::new();
+            for _ in 0..r.get_u32()? {
+                let name = r.get_str()?;
+                hyperparameters.push((name, r.get_u64()? as usize));
+            }
+            let saved = LayerConfig {
+                kind,
+                hyperparameters,
+            };
+            let config = layer.config();
+            if saved != config {
+                return Err(invalid(format!(
+                    "layer {} is {:?} in the checkpoint, {:?} in the model",
+                    idx, saved, config
+                )));
+            }
+        }
+        let head = r.get_str()?;
+        if head != self.head.kind() {
+            return Err(invalid(format!(
+                "the head is {} in the checkpoint, {} in the model",
+                head,
+                self.head.kind()
+            )));
+        }
+        let parameters = self.parameters();
+        let count = r.get_u32()? as usize;
+        if count != parameters.len() {
+            return Err(invalid(format!(
+                "checkpoint has {} parameters, the model {}",
+                count,
+                parameters.len()
+            )));
+        }
+        let mut values = Vec::new();
+        for parameter in parameters.iter() {
+            let name = r.get_str()?;
+            let value = r.get_matrix()?;
+            if name != parameter.name || value.shape() != parameter.value.shape() {
+                return Err(invalid(format!(
+                    "parameter {} {:?} in the checkpoint, {} {:?} in the model",
+                    name,
+                    value.shape(),
+                    parameter.name,
+                    parameter.value.shape()
+                )));
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+    pub unsafe fn set_weights(&mut self, values: Vec<Matrix>) {
+        for (parameter, value) in self.parameters().into_iter().zip(values) {
+            assign(&parameter.value, &value);
+        }
+    }
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        let mut w = Writer::new(MAGIC);
+        unsafe {
+            self.write_weights(&mut w);
+        }
+        w.save(path)
+    }
+    // weights trained elsewhere, see pretrained::load_tensors for the naming and the layouts
+    pub fn load_pretrained(
+        &mut self,
+        tensors: &HashMap<String, Tensor>,
+        sources: &[(usize, &str)],
+    ) -> Result<usize> {
+        unsafe { load_tensors(&mut self.layers, tensors, sources) }
+    }
+    // an ONNX model of the layers and the head's prediction, for the layers onnx::export knows
+    pub fn export_onnx(&mut self, path: &str) -> Result<()> {
+        unsafe { onnx::export(&mut self.layers, self.head.as_ref(), path) }
+    }
+    // fails without touching the weights if the file is damaged or from another model
+    pub fn load(&mut self, path: &str) -> Result<()> {
+        let mut r = Reader::open(path, MAGIC)?;
+        unsafe {
+            let values = self.read_weights(&mut r)?;
+            r.finish()?;
+            self.set_weights(values);
+        }
+        Ok(())
+    }
+    // the weights, the optimizer buffers, the progress and the state of every scheduler,
+    // for a run that continues exactly where this one stopped
+    pub fn save_training(
+        &mut self,
+        path: &str,
+        progress: &Progress,
+        schedulers: &[&dyn Stateful],
+    ) -> Result<()> {
+        let mut w = Writer::new(TRAINING_MAGIC);
+        unsafe {
+            self.write_weights(&mut w);
+            let parameters = unique_parameters(&mut self.layers, &mut self.head);
+            self.opt.write_state(&parameters, &mut w);
+        }
+        progress.write_state(&mut w);
+        w.put_u32(schedulers.len() as u32);
+        for scheduler in schedulers {
+            scheduler.write_state(&mut w);
+        }
+        w.save(path)
+    }
+    // schedulers in the order they were saved in
+    // the weights are untouched on failure, the optimizer and schedulers may not be
+    pub fn load_training(
+        &mut self,
+        path: &str,
+        schedulers: &mut [&mut dyn Stateful],
+    ) -> Result<Progress> {
+        let mut r = Reader::open(path, TRAINING_MAGIC)?;
+        unsafe {
+            let values = self.read_weights(&mut r)?;
+            let parameters = unique_parameters(&mut self.layers, &mut self.head);
+            self.opt.read_state(&parameters, &mut r)?;
+            let mut progress = Progress::default();
+            progress.read_state(&mut r)?;
+            let count = r.get_u32()? as usize;
+            if count != schedulers.len() {
+                return Err(invalid(format!(
+                    "checkpoint has {} schedulers, given {}",
+                    count,
+                    schedulers.len()
+                )));
+            }
+            for scheduler in schedulers.iter_mut() {
+                scheduler.read_state(&mut r)?;
+            }
+            r.finish()?;
+            self.set_weights(values);
+            Ok(progress)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::conv3x3::Conv3x3;
+    use crate::utils::dataloader::DataLoader;
+    use crate::utils::gradcheck::{onehot, rand_mat};
+    use crate::utils::head::SoftMaxCrossEntropy;
+    use crate::utils::linear::LinearLayer;
+    use crate::utils::metric::AngularMarginHead;
+    use crate::utils::nn_trait::DataSet;
+    use crate::utils::optimizer::{Adam, SGD};
+    use crate::utils::regression::MeanSquaredError;
+    use crate::utils::relu::ReluLayer;
+    use crate::utils::scheduler::{Scheduler, StepDecay};
+
+    #[test]
+    fn shared_parameters_sum_their_grads_and_step_once() {
+        unsafe {
+            let mut seed = 3u32;
+            let mut first = LinearLayer::new(4, 4);
+            first.weight.value = rand_mat(4, 4, &mut seed);
+            let weight = first.weight.share();
+            let second = LinearLayer::from_parameters(
+                weight,
+                Parameter::new("bias", rand_mat(1, 4, &mut seed), false),
+                false,
+            );
+            let layers: Vec<Box<dyn Layer>> = vec![Box::new(first), Box::new(second)];
+            let rate = 0.1;
+            let mut network = Network::new(
+                layers,
+                Box::new(SoftMaxCrossEntropy::new()),
+                Box::new(SGD::new(rate, 0.0, 0.0)),
+            );
+            assert_eq!(network.parameters().len(), 3);
+
+            let x = rand_mat(5, 4, &mut seed);
+            let target = Matrix::new(5, 4);
+            target.fill_(0.0);
+            for i in 0..5 {
+                *target.row_at(i).add(i as usize % 4) = 1.0;
+            }
+            let pred = network.forward(x);
+            let loss = network.calc_loss(pred, target);
+            network.backward(loss);
+            // the handle of every layer holds the grad of its own use
+            let grads: Vec<Matrix> = network
+                .layers
+                .iter_mut()
+                .map(|layer| layer.parameters()[0].grad.clone())
+                .collect();
+            let before = network.layers[0].parameters()[0].value.clone();
+            network.update_parameters();
+            for i in 0..4 {
+                for j in 0..4 {
+                    let grad = grads[0].at(i, j) + grads[1].at(i, j);
+                    let expected = before.at(i, j) - rate * grad;
+                    for layer in network.layers.iter_mut() {
+                        let value = layer.parameters()[0].value.at(i, j);
+                        assert!((value - expected).abs() < 1e-6);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn groups_select_by_layer_and_name() {
+        unsafe {
+            let layers: Vec<Box<dyn Layer>> = vec![
+                Box::new(LinearLayer::new(4, 4)),
+                Box::new(LinearLayer::new(4, 3)),
+            ];
+            let mut network = Network::new(
+                layers,
+                Box::new(SoftMaxCrossEntropy::new()),
+                Box::new(SGD::new(0.1, 0.0, 0.0)),
+            );
+            let no_decay = GroupOptions {
+                decay: Some(0.0),
+                ..GroupOptions::default()
+            };
+            let slow = GroupOptions {
+                rate_scale: 0.1,
+                ..GroupOptions::default()
+            };
+            let selector = ParameterSelector::NameContains("bias".to_string());
+            assert_eq!(network.set_group(&selector, no_decay), 2);
+            // the later call wins for the bias of the second layer
+            assert_eq!(network.set_group(&ParameterSelector::Layers(1..2), slow), 2);
+            let groups: Vec<GroupOptions> = network
+                .layers
+                .iter_mut()
+                .flat_map(|layer| layer.parameters().into_iter().map(|p| p.group))
+                .collect();
+            assert!(groups == vec![GroupOptions::default(), no_decay, slow, slow]);
+        }
+    }
+
+    // a linear layer into a head with weights of its own, all set from the seed
+    unsafe fn margin_network(rate: f32) -> Network {
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(LinearLayer::new(4, 5))];
+        let mut network = Network::new(
+            layers,
+            Box::new(AngularMarginHead::arcface(5, 3, 4.0, 0.3)),
+            Box::new(SGD::new(rate, 0.9, 0.0)),
+        );
+        let mut seed = 11u32;
+        for parameter in network.parameters() {
+            let (h, w) = parameter.value.shape();
+            parameter.value = rand_mat(h, w, &mut seed);
+        }
+        network
+    }
+
+    unsafe fn rows(x: &Matrix, from: usize, to: usize) -> Matrix {
+        let w = x.number_of_col();
+        let ret = Matrix::new(to - from, w);
+        for i in from..to {
+            for j in 0..w {
+                *ret.row_at((i - from) as isize).add(j) = x.at(i as isize, j as isize);
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn micro_batches_match_the_full_batch() {
+        unsafe {
+            let mut seed = 5u32;
+            let x = rand_mat(6, 4, &mut seed);
+            let target = onehot(&[0, 1, 2, 2, 1, 0], 3);
+            let mut full = margin_network(0.1);
+            // two updates, so the grads must also be cleared by the first
+            for _ in 0..2 {
+                full.accumulate(x.clone(), target.clone(), 1.0);
+                full.update_parameters();
+            }
+            // a loss scale of 1 / 3 with three times the rate takes the same steps,
+            // for the head's own weight as well
+            for scale in [1.0, 1.0 / 3.0] {
+                let mut micro = margin_network(0.1 / scale);
+                for _ in 0..2 {
+                    for k in 0..3 {
+                        let (from, to) = (2 * k, 2 * k + 2);
+                        micro.accumulate(rows(&x, from, to), rows(&target, from, to), scale);
+                    }
+                    micro.update_parameters();
+                }
+                for (a, b) in full.parameters().iter().zip(micro.parameters().iter()) {
+                    let (h, w) = a.value.shape();
+                    for i in 0..h as isize {
+                        for j in 0..w as isize {
+                            assert!((a.value.at(i, j) - b.value.at(i, j)).abs() < 1e-5);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("tinynet_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    unsafe fn conv_network(stride: usize, classes: usize, head: Box<dyn Head>) -> Network {
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(Conv3x3::new(1, 2, 4, 4, stride, 1)),
+            Box::new(ReluLayer::new()),
+            Box::new(LinearLayer::new(32, classes)),
+        ];
+        Network::new(layers, head, Box::new(SGD::new(0.1, 0.0, 0.0)))
+    }
+
+    unsafe fn bits(m: &Matrix) -> Vec<u32> {
+        let (h, w) = m.shape();
+        (0..h * w)
+            .map(|i| m.at((i / w) as isize, (i % w) as isize).to_bits())
+            .collect()
+    }
+
+    #[test]
+    fn weights_round_trip() {
+        unsafe {
+            let mut seed = 3u32;
+            let x = rand_mat(5, 16, &mut seed);
+            let path = temp_path("round_trip.tnnw");
+            let mut saved = conv_network(1, 3, Box::new(SoftMaxCrossEntropy::new()));
+            saved.save(&path).unwrap();
+
+            let mut loaded = conv_network(1, 3, Box::new(SoftMaxCrossEntropy::new()));
+            for parameter in loaded.parameters() {
+                parameter.value.fill_(0.5);
+            }
+            loaded.load(&path).unwrap();
+            assert_eq!(
+                bits(&saved.forward(x.clone())),
+                bits(&loaded.forward(x.clone()))
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn mismatched_checkpoints_are_refused() {
+        unsafe {
+            let mut seed = 3u32;
+            let x = rand_mat(5, 16, &mut seed);
+            let path = temp_path("mismatch.tnnw");
+            conv_network(1, 3, Box::new(SoftMaxCrossEntropy::new()))
+                .save(&path)
+                .unwrap();
+
+            // other hyperparameters, other shapes and another head
+            let mut other = conv_network(2, 3, Box::new(SoftMaxCrossEntropy::new()));
+            assert!(other.load(&path).is_err());
+            let mut other = conv_network(1, 4, Box::new(SoftMaxCrossEntropy::new()));
+            let before = bits(&other.forward(x.clone()));
+            assert!(other.load(&path).is_err());
+            assert_eq!(before, bits(&other.forward(x.clone())));
+            let mut other = conv_network(1, 3, Box::new(MeanSquaredError::new()));
+            let err = other.load(&path).unwrap_err();
+            assert!(err.to_string().contains("head"));
+
+            // a flipped byte fails the checksum
+            let mut bytes = std::fs::read(&path).unwrap();
+            let mid = bytes.len() / 2;
+            bytes[mid] ^= 1;
+            std::fs::write(&path, &bytes).unwrap();
+            let mut other = conv_network(1, 3, Box::new(SoftMaxCrossEntropy::new()));
+            let err = other.load(&path).unwrap_err();
+            assert!(err.to_string().contains("checksum"));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    struct ToySet {
+        images: Vec<Vec<f32>>,
+    }
+
+    impl DataSet for ToySet {
+        fn dim(&self) -> usize {
+            16
+        }
+        fn len(&self) -> usize {
+            self.images.len()
+        }
+        fn is_empty(&self) -> bool {
+            self.images.is_empty()
+        }
+        unsafe fn fetch_item(&self, idx: isize) -> (&[f32], u8) {
+            (&self.images[idx as usize], (idx % 10) as u8)
+        }
+    }
+
+    unsafe fn toy_set() -> ToySet {
+        let mut seed = 9u32;
+        let x = rand_mat(44, 16, &mut seed);
+        let images = (0..44)
+            .map(|i| {
+                let mut image: Vec<f32> = (0..16).map(|j| x.at(i, j)).collect();
+                image[i as usize % 10] += 3.0;
+                image
+            })
+            .collect();
+        ToySet { images }
+    }
+
+    unsafe fn mlp(adam: bool) -> Network {
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(LinearLayer::new(16, 12)),
+            Box::new(ReluLayer::new()),
+            Box::new(LinearLayer::new(12, 10)),
+        ];
+        let opt: Box<dyn Optimizer> = if adam {
+            Box::new(Adam::new(0.01, 0.9, 0.999, 1e-8, 0.001, true))
+        } else {
+            Box::new(SGD::nesterov(0.05, 0.9, 0.001))
+        };
+        Network::new(layers, Box::new(SoftMaxCrossEntropy::new()), opt)
+    }
+
+    // two epochs from progress, or until iteration stop
+    unsafe fn run(
+        network: &mut Network,
+        scheduler: &mut StepDecay,
+        set: &ToySet,
+        mut progress: Progress,
+        stop: Option<usize>,
+    ) -> Progress {
+        for epoch in progress.epoch..2 {
+            let seed = (epoch as u32) << 10 | 7;
+            let position = if epoch == progress.epoch {
+                progress.loader_position
+            } else {
+                0
+            };
+            let mut loader = DataLoader::resume(set, 8, seed, position);
+            loop {
+                if Some(progress.iteration) == stop {
+                    return Progress {
+                        epoch,
+                        loader_seed: seed,
+                        loader_position: loader.position(),
+                        ..progress
+                    };
+                }
+                let Some((image, gt)) = loader.next() else {
+                    break;
+                };
+                scheduler.step(network.opt.as_mut());
+                let pred = network.forward(image);
+                let loss = network.calc_loss(pred, gt);
+                network.backward(loss);
+                network.update_parameters();
+                progress.iteration += 1;
+            }
+        }
+        Progress {
+            epoch: 2,
+            loader_position: 0,
+            ..progress
+        }
+    }
+
+    unsafe fn weight_bits(network: &mut Network) -> Vec<u32> {
+        network
+            .parameters()
+            .iter()
+            .flat_map(|parameter| bits(&parameter.value))
+            .collect()
+    }
+
+    #[test]
+    fn resumed_training_is_exact() {
+        unsafe {
+            let set = toy_set();
+            let path = temp_path("resume.tnts");
+            for adam in [false, true] {
+                let mut full = mlp(adam);
+                let mut scheduler = StepDecay::new(3, 0.5);
+                run(&mut full, &mut scheduler, &set, Progress::default(), None);
+
+                // stops in the middle of the second epoch
+                let mut half = mlp(adam);
+                let mut scheduler = StepDecay::new(3, 0.5);
+                let progress = run(
+                    &mut half,
+                    &mut scheduler,
+                    &set,
+                    Progress::default(),
+                    Some(8),
+                );
+                half.save_training(&path, &progress, &[&scheduler]).unwrap();
+
+                let mut resumed = mlp(adam);
+                for parameter in resumed.parameters() {
+                    parameter.value.fill_(0.25);
+                }
+                let mut scheduler = StepDecay::new(3, 0.5);
+                let loaded = resumed.load_training(&path, &mut [&mut scheduler]).unwrap();
+                assert_eq!(progress, loaded);
+                run(&mut resumed, &mut scheduler, &set, loaded, None);
+                assert_eq!(weight_bits(&mut full), weight_bits(&mut resumed));
+
+                // another optimizer or scheduler list is refused
+                assert!(mlp(!adam)
+                    .load_training(&path, &mut [&mut StepDecay::new(3, 0.5)])
+                    .is_err());
+                assert!(mlp(adam).load_training(&path, &mut []).is_err());
             }
+            std::fs::remove_file(&path).unwrap();
         }
     }
 }
@@ -1,27 +1,122 @@
+use crate::utils::checkpoint::{Reader, Writer};
+use crate::utils::head::{arg_max, top_k};
 use crate::utils::mat::Matrix;
+use crate::utils::parameter::Parameter;
+use std::io::Result;
 
 pub trait Layer {
     fn forward(&mut self, input: Matrix) -> Matrix;
     fn backward(&mut self, dLoss: Matrix) -> Matrix;
-    fn trainable(&self) -> bool;
-    fn parameters(
-        &mut self,
-    ) -> Option<(
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-        &mut Matrix,
-    )> {
-        None
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        Vec::new()
+    }
+    // recorded in checkpoints, a checkpoint only loads into layers with the same config
+    fn config(&self) -> LayerConfig;
+}
+
+// the layer type and its constructor hyperparameters, flags as 0 / 1
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LayerConfig {
+    pub kind: String,
+    pub hyperparameters: Vec<(String, usize)>,
+}
+
+impl LayerConfig {
+    pub fn new(kind: &str, hyperparameters: &[(&str, usize)]) -> Self {
+        Self {
+            kind: kind.to_string(),
+            hyperparameters: hyperparameters
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        }
+    }
+}
+
+pub enum Prediction {
+    // one class index per row
+    Classes(Vec<usize>),
+    // raw network output, for regression
+    Values(Matrix),
+    // independent 0 / 1 decision and probability for every class
+    MultiLabel {
+        decisions: Matrix,
+        probabilities: Matrix,
+    },
+    // softmax over the classes of every row
+    Distribution {
+        probabilities: Matrix,
+        log_probabilities: Matrix,
+    },
+    // decoded label sequence of every row
+    Sequences(Vec<Vec<usize>>),
+}
+
+impl Prediction {
+    pub fn classes(self) -> Vec<usize> {
+        match self {
+            Prediction::Classes(x) => x,
+            Prediction::Distribution { probabilities, .. } => unsafe { arg_max(&probabilities) },
+            _ => panic!("prediction holds no classes"),
+        }
+    }
+    pub fn sequences(self) -> Vec<Vec<usize>> {
+        match self {
+            Prediction::Sequences(x) => x,
+            _ => panic!("prediction holds no sequences"),
+        }
+    }
+    pub fn probabilities(self) -> Matrix {
+        match self {
+            Prediction::Distribution { probabilities, .. } => probabilities,
+            Prediction::MultiLabel { probabilities, .. } => probabilities,
+            _ => panic!("prediction holds no probabilities"),
+        }
+    }
+    pub fn log_probabilities(self) -> Matrix {
+        match self {
+            Prediction::Distribution {
+                log_probabilities, ..
+            } => log_probabilities,
+            _ => panic!("prediction holds no log probabilities"),
+        }
+    }
+    // the k most probable classes of every row with their probabilities, best first
+    pub fn top_k(&self, k: usize) -> Vec<Vec<(usize, f32)>> {
+        match self {
+            Prediction::Distribution { probabilities, .. } => unsafe { top_k(probabilities, k) },
+            Prediction::MultiLabel { probabilities, .. } => unsafe { top_k(probabilities, k) },
+            _ => panic!("prediction holds no probabilities"),
+        }
+    }
+    pub fn values(self) -> Matrix {
+        match self {
+            Prediction::Values(x) => x,
+            _ => panic!("prediction holds no values"),
+        }
     }
 }
 
 pub trait Head {
     fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix;
     fn backward(&mut self, dLoss: Matrix) -> Matrix;
-    fn eval_forward(&self, input: Matrix) -> Vec<usize>;
+    // backward with the gradient times scale, for the layers and the head's own parameters
+    fn backward_scaled(&mut self, dLoss: Matrix, scale: f32) -> Matrix {
+        let ret = self.backward(dLoss);
+        if scale != 1.0 {
+            unsafe {
+                ret.mul_with_numeric(scale, true);
+            }
+        }
+        ret
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction;
+    // heads with weights of their own, trained along with the layers
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        Vec::new()
+    }
+    // the type name, checkpoints record it and exporters pick the output op by it
+    fn kind(&self) -> &'static str;
 }
 
 pub trait DataSet {
@@ -32,13 +127,11 @@ pub trait DataSet {
 }
 
 pub trait Optimizer {
-    fn step(
-        &self,
-        weight: &mut Matrix,
-        d_weight: &mut Matrix,
-        v_weight: &mut Matrix,
-        bias: &mut Matrix,
-        d_bias: &mut Matrix,
-        v_bias: &mut Matrix,
-    );
+    fn step(&mut self, parameters: Vec<&mut Parameter>);
+    fn rate(&self) -> f32;
+    // schedulers change the learning rate between steps through this
+    fn set_rate(&mut self, rate: f32);
+    // the buffers of these parameters in this order, ids differ from run to run
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer);
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()>;
 }
@@ -0,0 +1,581 @@
+use crate::utils::mat::Matrix;
+use crate::utils::nn_trait::{Head, Layer, LayerConfig};
+use std::io::{Error, ErrorKind, Result};
+
+const IR_VERSION: i64 = 7;
+const OPSET_VERSION: i64 = 13;
+const FLOAT: i64 = 1;
+const INT64: i64 = 7;
+const ATTRIBUTE_INT: i64 = 2;
+const ATTRIBUTE_INTS: i64 = 7;
+
+// protobuf wire format, fields are written in the order they are added
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut x: u64) {
+        while x >= 0x80 {
+            self.buf.push(x as u8 | 0x80);
+            x >>= 7;
+        }
+        self.buf.push(x as u8);
+    }
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+    // negative numbers take ten bytes, as in protobuf
+    fn int(&mut self, field: u32, x: i64) {
+        self.key(field, 0);
+        self.varint(x as u64);
+    }
+    fn bytes(&mut self, field: u32, x: &[u8]) {
+        self.key(field, 2);
+        self.varint(x.len() as u64);
+        self.buf.extend_from_slice(x);
+    }
+    fn string(&mut self, field: u32, x: &str) {
+        self.bytes(field, x.as_bytes());
+    }
+    fn message(&mut self, field: u32, x: Message) {
+        self.bytes(field, &x.buf);
+    }
+}
+
+fn unsupported(msg: String) -> Error {
+    Error::new(ErrorKind::Unsupported, msg)
+}
+
+// ValueInfoProto of a float tensor, the batch dimension is symbolic
+fn value_info(name: &str, dims: &[usize]) -> Message {
+    let mut shape = Message::default();
+    let mut batch = Message::default();
+    batch.string(2, "N");
+    shape.message(1, batch);
+    for &x in dims {
+        let mut dim = Message::default();
+        dim.int(1, x as i64);
+        shape.message(1, dim);
+    }
+    let mut tensor = Message::default();
+    tensor.int(1, FLOAT);
+    tensor.message(2, shape);
+    let mut ty = Message::default();
+    ty.message(1, tensor);
+    let mut ret = Message::default();
+    ret.string(1, name);
+    ret.message(2, ty);
+    ret
+}
+
+fn int_attribute(name: &str, x: i64) -> Message {
+    let mut ret = Message::default();
+    ret.string(1, name);
+    ret.int(3, x);
+    ret.int(20, ATTRIBUTE_INT);
+    ret
+}
+
+fn ints_attribute(name: &str, xs: &[i64]) -> Message {
+    let mut ret = Message::default();
+    ret.string(1, name);
+    for &x in xs {
+        ret.int(8, x);
+    }
+    ret.int(20, ATTRIBUTE_INTS);
+    ret
+}
+
+// the graph being built, tensors are named t0, t1, .. in the order they are made
+struct Graph {
+    nodes: Vec<Message>,
+    initializers: Vec<Message>,
+    count: usize,
+}
+
+impl Graph {
+    fn name(&mut self) -> String {
+        self.count += 1;
+        format!("t{}", self.count - 1)
+    }
+    fn node(&mut self, op: &str, inputs: &[&str], attributes: Vec<Message>) -> String {
+        let output = self.name();
+        self.node_to(op, inputs, attributes, &output);
+        output
+    }
+    fn node_to(&mut self, op: &str, inputs: &[&str], attributes: Vec<Message>, output: &str) {
+        let mut node = Message::default();
+        for input in inputs {
+            node.string(1, input);
+        }
+        node.string(2, output);
+        node.string(3, &format!("{}_{}", op, self.nodes.len()));
+        node.string(4, op);
+        for attribute in attributes {
+            node.message(5, attribute);
+        }
+        self.nodes.push(node);
+    }
+    fn float_tensor(&mut self, dims: &[usize], values: &[f32]) -> String {
+        let name = self.name();
+        let mut tensor = Message::default();
+        for &x in dims {
+            tensor.int(1, x as i64);
+        }
+        tensor.int(2, FLOAT);
+        tensor.string(8, &name);
+        let raw: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        tensor.bytes(9, &raw);
+        self.initializers.push(tensor);
+        name
+    }
+    fn int64_tensor(&mut self, values: &[i64]) -> String {
+        let name = self.name();
+        let mut tensor = Message::default();
+        tensor.int(1, values.len() as i64);
+        tensor.int(2, INT64);
+        tensor.string(8, &name);
+        let raw: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        tensor.bytes(9, &raw);
+        self.initializers.push(tensor);
+        name
+    }
+}
+
+// what flows between the nodes, images are NCHW in the graph and HWC rows in TinyNet
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Flat(usize),
+    Image { c: usize, h: usize, w: usize },
+}
+
+fn hyperparameter(config: &LayerConfig, name: &str) -> usize {
+    config
+        .hyperparameters
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| *value)
+        .unwrap()
+}
+
+unsafe fn values(x: &Matrix) -> Vec<f32> {
+    let (h, w) = x.shape();
+    (0..h)
+        .flat_map(|i| (0..w).map(move |j| x.at(i as isize, j as isize)))
+        .collect()
+}
+
+// the tensor as NCHW, from HWC rows with a Reshape and a Transpose if needed
+fn as_image(
+    graph: &mut Graph,
+    tensor: String,
+    layout: Layout,
+    c: usize,
+    h: usize,
+    w: usize,
+) -> Option<String> {
+    match layout {
+        Layout::Image { .. } if layout == (Layout::Image { c, h, w }) => Some(tensor),
+        Layout::Flat(n) if n == c * h * w => {
+            let shape = graph.int64_tensor(&[-1, h as i64, w as i64, c as i64]);
+            let nhwc = graph.node("Reshape", &[&tensor, &shape], vec![]);
+            let perm = ints_attribute("perm", &[0, 3, 1, 2]);
+            Some(graph.node("Transpose", &[&nhwc], vec![perm]))
+        }
+        _ => None,
+    }
+}
+
+// the tensor as HWC rows, from NCHW with a Transpose and a Flatten if needed
+fn as_rows(graph: &mut Graph, tensor: String, layout: Layout) -> (String, usize) {
+    match layout {
+        Layout::Flat(n) => (tensor, n),
+        Layout::Image { c, h, w } => {
+            let perm = ints_attribute("perm", &[0, 2, 3, 1]);
+            let nhwc = graph.node("Transpose", &[&tensor], vec![perm]);
+            let axis = int_attribute("axis", 1);
+            (graph.node("Flatten", &[&nhwc], vec![axis]), c * h * w)
+        }
+    }
+}
+
+fn unmatched(idx: usize, kind: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "layer {} ({}) does not match the output of the layer before",
+            idx, kind
+        ),
+    )
+}
+
+// Conv3x3, MaxPool2x2, ReluLayer and LinearLayer followed by a softmax or a regression head
+// the graph input is NCHW when the first layer works on images, (N, features) otherwise
+pub unsafe fn export(layers: &mut [Box<dyn Layer>], head: &dyn Head, path: &str) -> Result<()> {
+    let mut graph = Graph {
+        nodes: Vec::new(),
+        initializers: Vec::new(),
+        count: 0,
+    };
+    let configs: Vec<LayerConfig> = layers.iter().map(|layer| layer.config()).collect();
+    let input_layout = match configs.first() {
+        Some(config) if config.kind == "Conv3x3" || config.kind == "MaxPool2x2" => Layout::Image {
+            c: hyperparameter(config, "in_channels"),
+            h: hyperparameter(config, "im_row"),
+            w: hyperparameter(config, "im_col"),
+        },
+        Some(config) if config.kind == "LinearLayer" => {
+            Layout::Flat(hyperparameter(config, "in_channels"))
+        }
+        Some(config) => {
+            return Err(unsupported(format!(
+                "layer 0 ({}) has no ONNX export",
+                config.kind
+            )))
+        }
+        None => {
+            return Err(unsupported(
+                "an empty network has no ONNX export".to_string(),
+            ))
+        }
+    };
+    let mut tensor = "input".to_string();
+    let mut layout = input_layout;
+
+    for (idx, (layer, config)) in layers.iter_mut().zip(configs.iter()).enumerate() {
+        let kind = config.kind.as_str();
+        let get = |name: &str| hyperparameter(config, name);
+        match kind {
+            "Conv3x3" => {
+                let (c, h, w) = (get("in_channels"), get("im_row"), get("im_col"));
+                let (out, stride, padding) = (get("out_channels"), get("stride"), get("padding"));
+                tensor = as_image(&mut graph, tensor, layout, c, h, w)
+                    .ok_or_else(|| unmatched(idx, kind))?;
+                // no 3x3 window fits, the output size below would underflow
+                if h + 2 * padding < 3 || w + 2 * padding < 3 || stride == 0 {
+                    return Err(unsupported(format!(
+                        "layer {} ({}) has no output for a {}x{} input with padding {} and stride {}",
+                        idx, kind, h, w, padding, stride
+                    )));
+                }
+                let parameters = layer.parameters();
+                // rows of the weight are (ky * 3 + kx) * C + c, ONNX wants [out, C, ky, kx]
+                let weight = &parameters[0].value;
+                let mut kernel = vec![0f32; out * c * 9];
+                for o in 0..out {
+                    for ch in 0..c {
+                        for k in 0..9 {
+                            kernel[(o * c + ch) * 9 + k] =
+                                weight.at((k * c + ch) as isize, o as isize);
+                        }
+                    }
+                }
+                let kernel = graph.float_tensor(&[out, c, 3, 3], &kernel);
+                let bias = graph.float_tensor(&[out], &values(&parameters[1].value));
+                let padding = padding as i64;
+                tensor = graph.node(
+                    "Conv",
+                    &[&tensor, &kernel, &bias],
+                    vec![
+                        ints_attribute("kernel_shape", &[3, 3]),
+                        ints_attribute("pads", &[padding; 4]),
+                        ints_attribute("strides", &[stride as i64; 2]),
+                    ],
+                );
+                layout = Layout::Image {
+                    c: out,
+                    h: (h + 2 * padding as usize - 3) / stride + 1,
+                    w: (w + 2 * padding as usize - 3) / stride + 1,
+                };
+            }
+            "MaxPool2x2" => {
+                let (c, h, w) = (get("in_channels"), get("im_row"), get("im_col"));
+                tensor = as_image(&mut graph, tensor, layout, c, h, w)
+                    .ok_or_else(|| unmatched(idx, kind))?;
+                // odd sizes keep the last row / col in a window of their own
+                tensor = graph.node(
+                    "MaxPool",
+                    &[&tensor],
+                    vec![
+                        int_attribute("ceil_mode", 1),
+                        ints_attribute("kernel_shape", &[2, 2]),
+                        ints_attribute("strides", &[2, 2]),
+                    ],
+                );
+                layout = Layout::Image {
+                    c,
+                    h: h.div_ceil(2),
+                    w: w.div_ceil(2),
+                };
+            }
+            "ReluLayer" => {
+                tensor = graph.node("Relu", &[&tensor], vec![]);
+            }
+            "LinearLayer" => {
+                let (rows, n) = as_rows(&mut graph, tensor, layout);
+                if n != get("in_channels") {
+                    return Err(unmatched(idx, kind));
+                }
+                let parameters = layer.parameters();
+                let weight = &parameters[0].value;
+                let weight_name = graph.float_tensor(
+                    &[weight.number_of_row(), weight.number_of_col()],
+                    &values(weight),
+                );
+                let bias =
+                    graph.float_tensor(&[get("out_channels")], &values(&parameters[1].value));
+                tensor = graph.node(
+                    "Gemm",
+                    &[&rows, &weight_name, &bias],
+                    vec![int_attribute("transB", get("transpose") as i64)],
+                );
+                layout = Layout::Flat(get("out_channels"));
+            }
+            _ => {
+                return Err(unsupported(format!(
+                    "layer {} ({}) has no ONNX export",
+                    idx, kind
+                )))
+            }
+        }
+    }
+
+    // the head's prediction, as TinyNet rows
+    let (rows, n) = as_rows(&mut graph, tensor, layout);
+    match head.kind() {
+        "SoftMaxCrossEntropy" | "SoftMaxFocalLoss" => graph.node_to(
+            "Softmax",
+            &[&rows],
+            vec![int_attribute("axis", 1)],
+            "output",
+        ),
+        "MeanSquaredError" | "MeanAbsoluteError" | "HuberLoss" => {
+            graph.node_to("Identity", &[&rows], vec![], "output")
+        }
+        kind => return Err(unsupported(format!("head {} has no ONNX export", kind))),
+    };
+
+    let mut body = Message::default();
+    for node in graph.nodes {
+        body.message(1, node);
+    }
+    body.string(2, "TinyNet");
+    for initializer in graph.initializers {
+        body.message(5, initializer);
+    }
+    let input_dims = match input_layout {
+        Layout::Flat(n) => vec![n],
+        Layout::Image { c, h, w } => vec![c, h, w],
+    };
+    body.message(11, value_info("input", &input_dims));
+    body.message(12, value_info("output", &[n]));
+
+    let mut opset = Message::default();
+    opset.string(1, "");
+    opset.int(2, OPSET_VERSION);
+    let mut model = Message::default();
+    model.int(1, IR_VERSION);
+    model.string(2, "TinyNet");
+    model.message(7, body);
+    model.message(8, opset);
+    std::fs::write(path, &model.buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::conv3x3::Conv3x3;
+    use crate::utils::head::SoftMaxCrossEntropy;
+    use crate::utils::linear::LinearLayer;
+    use crate::utils::maxpool2x2::MaxPool2x2;
+    use crate::utils::parameter::Parameter;
+    use crate::utils::relu::ReluLayer;
+
+    enum Field {
+        Int(u64),
+        Bytes(Vec<u8>),
+    }
+
+    // the (field, value) pairs of a message, enough of protobuf to read the export back
+    fn decode(buf: &[u8]) -> Vec<(u32, Field)> {
+        let mut pos = 0;
+        let varint = |pos: &mut usize| {
+            let mut ret = 0u64;
+            let mut shift = 0;
+            loop {
+                let b = buf[*pos];
+                *pos += 1;
+                ret |= ((b & 0x7f) as u64) << shift;
+                if b < 0x80 {
+                    return ret;
+                }
+                shift += 7;
+            }
+        };
+        let mut ret = Vec::new();
+        while pos < buf.len() {
+            let key = varint(&mut pos);
+            let value = match key & 7 {
+                0 => Field::Int(varint(&mut pos)),
+                2 => {
+                    let len = varint(&mut pos) as usize;
+                    pos += len;
+                    Field::Bytes(buf[pos - len..pos].to_vec())
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            ret.push(((key >> 3) as u32, value));
+        }
+        ret
+    }
+
+    fn messages(buf: &[u8], field: u32) -> Vec<Vec<u8>> {
+        decode(buf)
+            .into_iter()
+            .filter_map(|(f, value)| match value {
+                Field::Bytes(bytes) if f == field => Some(bytes),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn string(buf: &[u8], field: u32) -> String {
+        String::from_utf8(messages(buf, field).remove(0)).unwrap()
+    }
+
+    fn ints(buf: &[u8], field: u32) -> Vec<i64> {
+        decode(buf)
+            .into_iter()
+            .filter_map(|(f, value)| match value {
+                Field::Int(x) if f == field => Some(x as i64),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("tinynet_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn export_decodes_to_the_expected_graph() {
+        unsafe {
+            // flat rows into a 2x4x4 image, so the export has to lay them out as NCHW
+            let mut layers: Vec<Box<dyn Layer>> = vec![
+                Box::new(LinearLayer::new(8, 32)),
+                Box::new(Conv3x3::new(2, 3, 4, 4, 1, 1)),
+                Box::new(ReluLayer::new()),
+                Box::new(MaxPool2x2::new(3, 4, 4)),
+                Box::new(LinearLayer::new(12, 5)),
+            ];
+            let path = temp_path("export.onnx");
+            export(&mut layers, &SoftMaxCrossEntropy::new(), &path).unwrap();
+            let model = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let graph = messages(&model, 7).remove(0);
+            let nodes = messages(&graph, 1);
+            let ops: Vec<String> = nodes.iter().map(|node| string(node, 4)).collect();
+            assert_eq!(
+                ops,
+                vec![
+                    "Gemm",
+                    "Reshape",
+                    "Transpose",
+                    "Conv",
+                    "Relu",
+                    "MaxPool",
+                    "Transpose",
+                    "Flatten",
+                    "Gemm",
+                    "Softmax"
+                ]
+            );
+
+            let initializers = messages(&graph, 5);
+            let dims: Vec<Vec<i64>> = initializers.iter().map(|t| ints(t, 1)).collect();
+            assert_eq!(
+                dims,
+                vec![
+                    vec![8, 32],
+                    vec![32],
+                    vec![4],
+                    vec![3, 2, 3, 3],
+                    vec![3],
+                    vec![12, 5],
+                    vec![5]
+                ]
+            );
+
+            // the Reshape takes the HWC rows to [-1, h, w, c] and the Transpose to NCHW
+            let shape_name = messages(&nodes[1], 1).remove(1);
+            let shape = initializers
+                .iter()
+                .find(|t| messages(t, 8)[0] == shape_name)
+                .unwrap();
+            assert_eq!(ints(shape, 2), vec![INT64]);
+            let raw = messages(shape, 9).remove(0);
+            let values: Vec<i64> = raw
+                .chunks(8)
+                .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            assert_eq!(values, vec![-1, 4, 4, 2]);
+            let perm = messages(&nodes[2], 5).remove(0);
+            assert_eq!(string(&perm, 1), "perm");
+            assert_eq!(ints(&perm, 8), vec![0, 3, 1, 2]);
+            // and back to NHWC before the flatten
+            let perm = messages(&nodes[6], 5).remove(0);
+            assert_eq!(ints(&perm, 8), vec![0, 2, 3, 1]);
+
+            let conv_pads = messages(&nodes[3], 5)
+                .into_iter()
+                .find(|a| string(a, 1) == "pads")
+                .unwrap();
+            assert_eq!(ints(&conv_pads, 8), vec![1; 4]);
+        }
+    }
+
+    // reports the config of a conv too small for its window
+    struct TinyConv;
+
+    impl Layer for TinyConv {
+        fn forward(&mut self, input: Matrix) -> Matrix {
+            input
+        }
+        fn backward(&mut self, dLoss: Matrix) -> Matrix {
+            dLoss
+        }
+        fn parameters(&mut self) -> Vec<&mut Parameter> {
+            Vec::new()
+        }
+        fn config(&self) -> LayerConfig {
+            LayerConfig::new(
+                "Conv3x3",
+                &[
+                    ("in_channels", 1),
+                    ("out_channels", 1),
+                    ("im_row", 2),
+                    ("im_col", 2),
+                    ("stride", 1),
+                    ("padding", 0),
+                ],
+            )
+        }
+    }
+
+    #[test]
+    fn conv_without_an_output_is_refused() {
+        unsafe {
+            let mut layers: Vec<Box<dyn Layer>> = vec![Box::new(TinyConv)];
+            let path = temp_path("tiny.onnx");
+            let err = export(&mut layers, &SoftMaxCrossEntropy::new(), &path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unsupported);
+            assert!(!std::path::Path::new(&path).exists());
+        }
+    }
+}
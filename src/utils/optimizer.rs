@@ -1,10 +1,74 @@
+use crate::utils::checkpoint::{invalid, Reader, Writer};
 use crate::utils::mat::Matrix;
+use crate::utils::misc::{l2_norm, zeros};
 use crate::utils::nn_trait::Optimizer;
+use crate::utils::parameter::Parameter;
+use std::collections::HashMap;
+use std::io::Result;
+
+// grad + decay * value
+unsafe fn l2_grad(parameter: &Parameter, decay: f32) -> Matrix {
+    if decay != 0.0 {
+        let weight_decay = parameter.value.mul_with_numeric(decay, false).unwrap();
+        weight_decay.add(&parameter.grad, true);
+        weight_decay
+    } else {
+        parameter.grad.clone()
+    }
+}
+
+// every buffer for every parameter in order, with a flag for the ones not created yet
+unsafe fn write_buffers(
+    w: &mut Writer,
+    parameters: &[&mut Parameter],
+    buffers: &[&HashMap<usize, Matrix>],
+) {
+    for buffer in buffers {
+        for parameter in parameters {
+            match buffer.get(&parameter.id()) {
+                Some(x) => {
+                    w.put_bool(true);
+                    w.put_matrix(x);
+                }
+                None => w.put_bool(false),
+            }
+        }
+    }
+}
+
+unsafe fn read_buffers(
+    r: &mut Reader,
+    parameters: &[&mut Parameter],
+    count: usize,
+) -> Result<Vec<HashMap<usize, Matrix>>> {
+    let mut ret = Vec::new();
+    for _ in 0..count {
+        let mut buffer = HashMap::new();
+        for parameter in parameters {
+            if !r.get_bool()? {
+                continue;
+            }
+            let x = r.get_matrix()?;
+            if x.shape() != parameter.value.shape() {
+                return Err(invalid(format!(
+                    "optimizer state of {} does not match its shape",
+                    parameter.name
+                )));
+            }
+            buffer.insert(parameter.id(), x);
+        }
+        ret.push(buffer);
+    }
+    Ok(ret)
+}
 
 pub struct SGD {
     rate: f32,
     momentum: f32,
     decay: f32,
+    // steps along the velocity it is about to take, go + momentum * velocity
+    nesterov: bool,
+    velocity: HashMap<usize, Matrix>,
 }
 
 impl SGD {
@@ -13,42 +77,1097 @@ impl SGD {
             rate,
             momentum,
             decay,
+            nesterov: false,
+            velocity: HashMap::new(),
+        }
+    }
+    pub fn nesterov(rate: f32, momentum: f32, decay: f32) -> Self {
+        Self {
+            nesterov: true,
+            ..Self::new(rate, momentum, decay)
         }
     }
 }
 
 impl Optimizer for SGD {
-    fn step(
-        &self,
-        weight: &mut Matrix,
-        d_weight: &mut Matrix,
-        v_weight: &mut Matrix,
-        bias: &mut Matrix,
-        d_bias: &mut Matrix,
-        v_bias: &mut Matrix,
-    ) {
-        unsafe {
-            let weight_decay = weight.mul_with_numeric(self.decay, false).unwrap();
-            weight_decay.add(d_weight, true);
-            let weight_go = weight_decay.mul_with_numeric(-self.rate, false).unwrap();
-            if v_weight.is_null() {
-                *v_weight = weight_go;
-            } else {
-                v_weight.mul_with_numeric(self.momentum, true);
-                v_weight.add(&weight_go, true);
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("SGD");
+        w.put_f32(self.rate);
+        write_buffers(w, parameters, &[&self.velocity]);
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("SGD")?;
+        let rate = r.get_f32()?;
+        let mut buffers = read_buffers(r, parameters, 1)?;
+        self.velocity = buffers.pop().unwrap();
+        self.rate = rate;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        for parameter in parameters {
+            unsafe {
+                let momentum = parameter.momentum(self.momentum);
+                let go = l2_grad(parameter, parameter.weight_decay(self.decay));
+                go.mul_with_numeric(-parameter.rate(self.rate), true);
+                if let Some(velocity) = self.velocity.get_mut(&parameter.id()) {
+                    velocity.mul_with_numeric(momentum, true);
+                    velocity.add(&go, true);
+                } else {
+                    self.velocity.insert(parameter.id(), go.clone());
+                }
+                let velocity = &self.velocity[&parameter.id()];
+                if self.nesterov {
+                    go.add(&velocity.mul_with_numeric(momentum, false).unwrap(), true);
+                    parameter.value.add(&go, true);
+                } else {
+                    parameter.value.add(velocity, true);
+                }
+            }
+        }
+    }
+}
+
+// Adam, or AdamW when the weight decay is decoupled from the gradient
+// amsgrad divides by the largest second moment seen so far instead of the current one
+pub struct Adam {
+    rate: f32,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    decay: f32,
+    decoupled: bool,
+    amsgrad: bool,
+    step_count: i32,
+    first_moment: HashMap<usize, Matrix>,
+    second_moment: HashMap<usize, Matrix>,
+    max_second_moment: HashMap<usize, Matrix>,
+}
+
+impl Adam {
+    pub fn new(rate: f32, beta1: f32, beta2: f32, eps: f32, decay: f32, amsgrad: bool) -> Self {
+        Self {
+            rate,
+            beta1,
+            beta2,
+            eps,
+            decay,
+            decoupled: false,
+            amsgrad,
+            step_count: 0,
+            first_moment: HashMap::new(),
+            second_moment: HashMap::new(),
+            max_second_moment: HashMap::new(),
+        }
+    }
+    pub fn adamw(rate: f32, beta1: f32, beta2: f32, eps: f32, decay: f32, amsgrad: bool) -> Self {
+        Self {
+            decoupled: true,
+            ..Self::new(rate, beta1, beta2, eps, decay, amsgrad)
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("Adam");
+        w.put_f32(self.rate);
+        w.put_u32(self.step_count as u32);
+        write_buffers(
+            w,
+            parameters,
+            &[
+                &self.first_moment,
+                &self.second_moment,
+                &self.max_second_moment,
+            ],
+        );
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("Adam")?;
+        let rate = r.get_f32()?;
+        let step_count = r.get_u32()? as i32;
+        let mut buffers = read_buffers(r, parameters, 3)?.into_iter();
+        self.first_moment = buffers.next().unwrap();
+        self.second_moment = buffers.next().unwrap();
+        self.max_second_moment = buffers.next().unwrap();
+        self.rate = rate;
+        self.step_count = step_count;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        self.step_count += 1;
+        let correction1 = 1.0 - self.beta1.powi(self.step_count);
+        let correction2 = 1.0 - self.beta2.powi(self.step_count);
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let id = parameter.id();
+                let rate = parameter.rate(self.rate);
+                let decay = parameter.weight_decay(self.decay);
+                let grad = l2_grad(parameter, if self.decoupled { 0.0 } else { decay });
+
+                let m = self.first_moment.entry(id).or_insert_with(|| zeros(h, w));
+                m.mul_with_numeric(self.beta1, true);
+                m.add(
+                    &grad.mul_with_numeric(1.0 - self.beta1, false).unwrap(),
+                    true,
+                );
+
+                let v = self.second_moment.entry(id).or_insert_with(|| zeros(h, w));
+                let square = grad.dot(&grad, false).unwrap();
+                square.mul_with_numeric(1.0 - self.beta2, true);
+                v.mul_with_numeric(self.beta2, true);
+                v.add(&square, true);
+                let v = if self.amsgrad {
+                    let v_max = self
+                        .max_second_moment
+                        .entry(id)
+                        .or_insert_with(|| zeros(h, w));
+                    v_max.max(v, true);
+                    &*v_max
+                } else {
+                    &*v
+                };
+
+                // rate * m_hat / (sqrt(v_hat) + eps)
+                let denom = v.sqrt(false).unwrap();
+                denom.mul_with_numeric(1.0 / correction2.sqrt(), true);
+                denom.add_with_numeric(self.eps, true);
+                let update = self.first_moment[&id].div(&denom, false).unwrap();
+                update.mul_with_numeric(-rate / correction1, true);
+
+                if self.decoupled && decay != 0.0 {
+                    parameter.value.mul_with_numeric(1.0 - rate * decay, true);
+                }
+                parameter.value.add(&update, true);
+            }
+        }
+    }
+}
+
+// divides by a running root mean square of the grad
+// centered subtracts the square of the running mean grad, i.e. uses the variance
+pub struct RMSProp {
+    rate: f32,
+    alpha: f32,
+    eps: f32,
+    momentum: f32,
+    centered: bool,
+    decay: f32,
+    square_avg: HashMap<usize, Matrix>,
+    grad_avg: HashMap<usize, Matrix>,
+    velocity: HashMap<usize, Matrix>,
+}
+
+impl RMSProp {
+    pub fn new(rate: f32, alpha: f32, eps: f32, momentum: f32, centered: bool, decay: f32) -> Self {
+        Self {
+            rate,
+            alpha,
+            eps,
+            momentum,
+            centered,
+            decay,
+            square_avg: HashMap::new(),
+            grad_avg: HashMap::new(),
+            velocity: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for RMSProp {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("RMSProp");
+        w.put_f32(self.rate);
+        write_buffers(
+            w,
+            parameters,
+            &[&self.square_avg, &self.grad_avg, &self.velocity],
+        );
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("RMSProp")?;
+        let rate = r.get_f32()?;
+        let mut buffers = read_buffers(r, parameters, 3)?.into_iter();
+        self.square_avg = buffers.next().unwrap();
+        self.grad_avg = buffers.next().unwrap();
+        self.velocity = buffers.next().unwrap();
+        self.rate = rate;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let id = parameter.id();
+                let grad = l2_grad(parameter, parameter.weight_decay(self.decay));
+
+                let square = grad.dot(&grad, false).unwrap();
+                square.mul_with_numeric(1.0 - self.alpha, true);
+                let v = self.square_avg.entry(id).or_insert_with(|| zeros(h, w));
+                v.mul_with_numeric(self.alpha, true);
+                v.add(&square, true);
+
+                let denom = if self.centered {
+                    let g = self.grad_avg.entry(id).or_insert_with(|| zeros(h, w));
+                    g.mul_with_numeric(self.alpha, true);
+                    g.add(
+                        &grad.mul_with_numeric(1.0 - self.alpha, false).unwrap(),
+                        true,
+                    );
+                    let mean_square = g.dot(g, false).unwrap();
+                    mean_square.mul_with_numeric(-1.0, true);
+                    mean_square.add(v, true);
+                    // rounding can leave the variance just below zero
+                    mean_square.clamp(0.0, f32::MAX);
+                    mean_square
+                } else {
+                    v.clone()
+                };
+                denom.sqrt(true);
+                denom.add_with_numeric(self.eps, true);
+                grad.div(&denom, true);
+
+                let momentum = parameter.momentum(self.momentum);
+                let go = if momentum > 0.0 {
+                    let velocity = self.velocity.entry(id).or_insert_with(|| zeros(h, w));
+                    velocity.mul_with_numeric(momentum, true);
+                    velocity.add(&grad, true);
+                    velocity.clone()
+                } else {
+                    grad
+                };
+                go.mul_with_numeric(-parameter.rate(self.rate), true);
+                parameter.value.add(&go, true);
+            }
+        }
+    }
+}
+
+// divides by the root of the sum of every squared grad so far
+pub struct Adagrad {
+    rate: f32,
+    initial_accumulator: f32,
+    eps: f32,
+    decay: f32,
+    square_sum: HashMap<usize, Matrix>,
+}
+
+impl Adagrad {
+    pub fn new(rate: f32, initial_accumulator: f32, eps: f32, decay: f32) -> Self {
+        Self {
+            rate,
+            initial_accumulator,
+            eps,
+            decay,
+            square_sum: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for Adagrad {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("Adagrad");
+        w.put_f32(self.rate);
+        write_buffers(w, parameters, &[&self.square_sum]);
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("Adagrad")?;
+        let rate = r.get_f32()?;
+        let mut buffers = read_buffers(r, parameters, 1)?;
+        self.square_sum = buffers.pop().unwrap();
+        self.rate = rate;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let grad = l2_grad(parameter, parameter.weight_decay(self.decay));
+                let sum = self.square_sum.entry(parameter.id()).or_insert_with(|| {
+                    let sum = zeros(h, w);
+                    sum.add_with_numeric(self.initial_accumulator, true);
+                    sum
+                });
+                sum.add(&grad.dot(&grad, false).unwrap(), true);
+
+                let denom = sum.sqrt(false).unwrap();
+                denom.add_with_numeric(self.eps, true);
+                grad.div(&denom, true);
+                grad.mul_with_numeric(-parameter.rate(self.rate), true);
+                parameter.value.add(&grad, true);
+            }
+        }
+    }
+}
+
+// scales the grad by the ratio of the running rms of past updates to the running rms of grads
+// rate multiplies that update and is 1 in the paper
+pub struct Adadelta {
+    rate: f32,
+    rho: f32,
+    eps: f32,
+    decay: f32,
+    square_avg: HashMap<usize, Matrix>,
+    delta_avg: HashMap<usize, Matrix>,
+}
+
+impl Adadelta {
+    pub fn new(rate: f32, rho: f32, eps: f32, decay: f32) -> Self {
+        Self {
+            rate,
+            rho,
+            eps,
+            decay,
+            square_avg: HashMap::new(),
+            delta_avg: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for Adadelta {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("Adadelta");
+        w.put_f32(self.rate);
+        write_buffers(w, parameters, &[&self.square_avg, &self.delta_avg]);
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("Adadelta")?;
+        let rate = r.get_f32()?;
+        let mut buffers = read_buffers(r, parameters, 2)?.into_iter();
+        self.square_avg = buffers.next().unwrap();
+        self.delta_avg = buffers.next().unwrap();
+        self.rate = rate;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let id = parameter.id();
+                let grad = l2_grad(parameter, parameter.weight_decay(self.decay));
+
+                let square = grad.dot(&grad, false).unwrap();
+                square.mul_with_numeric(1.0 - self.rho, true);
+                let v = self.square_avg.entry(id).or_insert_with(|| zeros(h, w));
+                v.mul_with_numeric(self.rho, true);
+                v.add(&square, true);
+
+                // delta = sqrt(u + eps) / sqrt(v + eps) * grad
+                let u = self.delta_avg.entry(id).or_insert_with(|| zeros(h, w));
+                let delta = u.add_with_numeric(self.eps, false).unwrap();
+                delta.sqrt(true);
+                let denom = v.add_with_numeric(self.eps, false).unwrap();
+                denom.sqrt(true);
+                delta.div(&denom, true);
+                delta.dot(&grad, true);
+
+                let square = delta.dot(&delta, false).unwrap();
+                square.mul_with_numeric(1.0 - self.rho, true);
+                u.mul_with_numeric(self.rho, true);
+                u.add(&square, true);
+
+                delta.mul_with_numeric(-parameter.rate(self.rate), true);
+                parameter.value.add(&delta, true);
+            }
+        }
+    }
+}
+
+// SGD with momentum where every parameter's rate is scaled by its trust ratio
+// trust * |w| / (|g| + decay * |w| + eps)
+// exclude_bias keeps the plain rate for the parameters without weight decay, biases and norms
+pub struct LARS {
+    rate: f32,
+    momentum: f32,
+    decay: f32,
+    trust: f32,
+    eps: f32,
+    exclude_bias: bool,
+    velocity: HashMap<usize, Matrix>,
+}
+
+impl LARS {
+    pub fn new(
+        rate: f32,
+        momentum: f32,
+        decay: f32,
+        trust: f32,
+        eps: f32,
+        exclude_bias: bool,
+    ) -> Self {
+        Self {
+            rate,
+            momentum,
+            decay,
+            trust,
+            eps,
+            exclude_bias,
+            velocity: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for LARS {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("LARS");
+        w.put_f32(self.rate);
+        write_buffers(w, parameters, &[&self.velocity]);
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("LARS")?;
+        let rate = r.get_f32()?;
+        let mut buffers = read_buffers(r, parameters, 1)?;
+        self.velocity = buffers.pop().unwrap();
+        self.rate = rate;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let decay = parameter.weight_decay(self.decay);
+                let mut rate = parameter.rate(self.rate);
+                if !self.exclude_bias || parameter.decay {
+                    let weight_norm = l2_norm(&parameter.value);
+                    let grad_norm = l2_norm(&parameter.grad);
+                    if weight_norm > 0.0 && grad_norm > 0.0 {
+                        rate *=
+                            self.trust * weight_norm / (grad_norm + decay * weight_norm + self.eps);
+                    }
+                }
+                let go = l2_grad(parameter, decay);
+                go.mul_with_numeric(-rate, true);
+                let velocity = self
+                    .velocity
+                    .entry(parameter.id())
+                    .or_insert_with(|| zeros(h, w));
+                velocity.mul_with_numeric(parameter.momentum(self.momentum), true);
+                velocity.add(&go, true);
+                parameter.value.add(velocity, true);
+            }
+        }
+    }
+}
+
+// Adam's bias corrected step plus decoupled weight decay, scaled by the trust ratio
+// |w| / |step| of every parameter
+// exclude_bias keeps the plain rate for the parameters without weight decay, biases and norms
+pub struct LAMB {
+    rate: f32,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    decay: f32,
+    exclude_bias: bool,
+    step_count: i32,
+    first_moment: HashMap<usize, Matrix>,
+    second_moment: HashMap<usize, Matrix>,
+}
+
+impl LAMB {
+    pub fn new(
+        rate: f32,
+        beta1: f32,
+        beta2: f32,
+        eps: f32,
+        decay: f32,
+        exclude_bias: bool,
+    ) -> Self {
+        Self {
+            rate,
+            beta1,
+            beta2,
+            eps,
+            decay,
+            exclude_bias,
+            step_count: 0,
+            first_moment: HashMap::new(),
+            second_moment: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for LAMB {
+    fn rate(&self) -> f32 {
+        self.rate
+    }
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    unsafe fn write_state(&self, parameters: &[&mut Parameter], w: &mut Writer) {
+        w.put_str("LAMB");
+        w.put_f32(self.rate);
+        w.put_u32(self.step_count as u32);
+        write_buffers(w, parameters, &[&self.first_moment, &self.second_moment]);
+    }
+    unsafe fn read_state(&mut self, parameters: &[&mut Parameter], r: &mut Reader) -> Result<()> {
+        r.expect_str("LAMB")?;
+        let rate = r.get_f32()?;
+        let step_count = r.get_u32()? as i32;
+        let mut buffers = read_buffers(r, parameters, 2)?.into_iter();
+        self.first_moment = buffers.next().unwrap();
+        self.second_moment = buffers.next().unwrap();
+        self.rate = rate;
+        self.step_count = step_count;
+        Ok(())
+    }
+    fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        self.step_count += 1;
+        let correction1 = 1.0 - self.beta1.powi(self.step_count);
+        let correction2 = 1.0 - self.beta2.powi(self.step_count);
+        for parameter in parameters {
+            unsafe {
+                let (h, w) = parameter.value.shape();
+                let id = parameter.id();
+                let grad = &parameter.grad;
+
+                let m = self.first_moment.entry(id).or_insert_with(|| zeros(h, w));
+                m.mul_with_numeric(self.beta1, true);
+                m.add(
+                    &grad.mul_with_numeric(1.0 - self.beta1, false).unwrap(),
+                    true,
+                );
+
+                let v = self.second_moment.entry(id).or_insert_with(|| zeros(h, w));
+                let square = grad.dot(grad, false).unwrap();
+                square.mul_with_numeric(1.0 - self.beta2, true);
+                v.mul_with_numeric(self.beta2, true);
+                v.add(&square, true);
+
+                // m_hat / (sqrt(v_hat) + eps) + decay * w
+                let denom = v.sqrt(false).unwrap();
+                denom.mul_with_numeric(1.0 / correction2.sqrt(), true);
+                denom.add_with_numeric(self.eps, true);
+                let update = self.first_moment[&id].div(&denom, false).unwrap();
+                update.mul_with_numeric(1.0 / correction1, true);
+                let decay = parameter.weight_decay(self.decay);
+                if decay != 0.0 {
+                    update.add(
+                        &parameter.value.mul_with_numeric(decay, false).unwrap(),
+                        true,
+                    );
+                }
+
+                let mut ratio = 1.0;
+                if !self.exclude_bias || parameter.decay {
+                    let weight_norm = l2_norm(&parameter.value);
+                    let update_norm = l2_norm(&update);
+                    if weight_norm > 0.0 && update_norm > 0.0 {
+                        ratio = weight_norm / update_norm;
+                    }
+                }
+                update.mul_with_numeric(-parameter.rate(self.rate) * ratio, true);
+                parameter.value.add(&update, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::checkpoint::{Reader, Writer};
+    use crate::utils::parameter::GroupOptions;
+
+    const START: [f32; 2] = [1.0, -2.0];
+    // the second grad of the first weight is small enough for its second moment to shrink
+    const GRADS: [[f32; 2]; 2] = [[0.5, -1.0], [0.1, 0.3]];
+
+    unsafe fn row(values: &[f32]) -> Matrix {
+        let ret = Matrix::new(1, values.len());
+        for (j, &v) in values.iter().enumerate() {
+            *ret.row_at(0).add(j) = v;
+        }
+        ret
+    }
+
+    unsafe fn assert_row(m: &Matrix, expected: &[f64]) {
+        for (j, &e) in expected.iter().enumerate() {
+            let got = m.at(0, j as isize) as f64;
+            assert!((got - e).abs() < 1e-5, "{} != {} at {}", got, e, j);
+        }
+    }
+
+    // a weight that takes decay and a bias that does not, both stepped through GRADS
+    unsafe fn run(optimizer: &mut dyn Optimizer) -> (Parameter, Parameter) {
+        run_groups(optimizer, GroupOptions::default(), GroupOptions::default())
+    }
+
+    unsafe fn run_groups(
+        optimizer: &mut dyn Optimizer,
+        weight_group: GroupOptions,
+        bias_group: GroupOptions,
+    ) -> (Parameter, Parameter) {
+        let mut weight = Parameter::new("weight", row(&START), true);
+        let mut bias = Parameter::new("bias", row(&START), false);
+        weight.group = weight_group;
+        bias.group = bias_group;
+        for grad in GRADS.iter() {
+            weight.grad = row(grad);
+            bias.grad = row(grad);
+            optimizer.step(vec![&mut weight, &mut bias]);
+        }
+        (weight, bias)
+    }
+
+    // a scalar reference of the update rule applied to every element
+    fn expected(reference: impl Fn(f64, &[f64]) -> f64) -> Vec<f64> {
+        (0..2)
+            .map(|j| {
+                let grads: Vec<f64> = GRADS.iter().map(|g| g[j] as f64).collect();
+                reference(START[j] as f64, &grads)
+            })
+            .collect()
+    }
+
+    // rate 0.1 and both betas 0.9
+    fn adam_reference(
+        mut x: f64,
+        grads: &[f64],
+        decay: f64,
+        decoupled: bool,
+        amsgrad: bool,
+    ) -> f64 {
+        let (rate, beta1, beta2, eps) = (0.1, 0.9, 0.9f64, 1e-8);
+        let (mut m, mut v, mut v_max) = (0.0, 0.0, 0.0f64);
+        for (t, &g) in grads.iter().enumerate() {
+            let t = t as i32 + 1;
+            let g = if decoupled { g } else { g + decay * x };
+            m = beta1 * m + (1.0 - beta1) * g;
+            v = beta2 * v + (1.0 - beta2) * g * g;
+            v_max = v_max.max(v);
+            let v = if amsgrad { v_max } else { v };
+            if decoupled {
+                x *= 1.0 - rate * decay;
+            }
+            x -= rate * (m / (1.0 - beta1.powi(t))) / ((v / (1.0 - beta2.powi(t))).sqrt() + eps);
+        }
+        x
+    }
+
+    #[test]
+    fn adam_steps_match_the_update_rule() {
+        unsafe {
+            // the first step moves every weight by the rate against its grad
+            let mut optimizer = Adam::new(0.1, 0.9, 0.9, 1e-8, 0.0, false);
+            let mut weight = Parameter::new("weight", row(&START), true);
+            weight.grad = row(&GRADS[0]);
+            optimizer.step(vec![&mut weight]);
+            assert_row(&weight.value, &[0.9, -1.9]);
+
+            for (decay, decoupled, amsgrad) in [
+                (0.0, false, false),
+                (0.1, false, false),
+                (0.1, true, false),
+                (0.0, false, true),
+                (0.1, true, true),
+            ] {
+                let mut optimizer = if decoupled {
+                    Adam::adamw(0.1, 0.9, 0.9, 1e-8, decay, amsgrad)
+                } else {
+                    Adam::new(0.1, 0.9, 0.9, 1e-8, decay, amsgrad)
+                };
+                let (weight, bias) = run(&mut optimizer);
+                let decay = decay as f64;
+                assert_row(
+                    &weight.value,
+                    &expected(|x, g| adam_reference(x, g, decay, decoupled, amsgrad)),
+                );
+                assert_row(
+                    &bias.value,
+                    &expected(|x, g| adam_reference(x, g, 0.0, decoupled, amsgrad)),
+                );
+            }
+            // the max of the second moment does change the step
+            let plain = adam_reference(1.0, &[0.5, 0.1], 0.0, false, false);
+            let ams = adam_reference(1.0, &[0.5, 0.1], 0.0, false, true);
+            assert!((plain - ams).abs() > 1e-3);
+        }
+    }
+
+    // rate 0.1, alpha 0.9 and eps 1e-8
+    fn rmsprop_reference(
+        mut x: f64,
+        grads: &[f64],
+        momentum: f64,
+        centered: bool,
+        decay: f64,
+    ) -> f64 {
+        let (mut v, mut mean, mut velocity) = (0.0, 0.0, 0.0f64);
+        for &g in grads {
+            let g = g + decay * x;
+            v = 0.9 * v + 0.1 * g * g;
+            mean = 0.9 * mean + 0.1 * g;
+            let square = if centered { v - mean * mean } else { v };
+            velocity = momentum * velocity + g / (square.sqrt() + 1e-8);
+            x -= 0.1 * velocity;
+        }
+        x
+    }
+
+    #[test]
+    fn rmsprop_steps_match_the_update_rule() {
+        unsafe {
+            for (momentum, centered, decay) in [
+                (0.0, false, 0.0),
+                (0.5, false, 0.0),
+                (0.0, true, 0.0),
+                (0.5, true, 0.1),
+            ] {
+                let mut optimizer = RMSProp::new(0.1, 0.9, 1e-8, momentum, centered, decay);
+                let (weight, bias) = run(&mut optimizer);
+                let (momentum, decay) = (momentum as f64, decay as f64);
+                assert_row(
+                    &weight.value,
+                    &expected(|x, g| rmsprop_reference(x, g, momentum, centered, decay)),
+                );
+                assert_row(
+                    &bias.value,
+                    &expected(|x, g| rmsprop_reference(x, g, momentum, centered, 0.0)),
+                );
+            }
+            // by hand, the first plain step is rate * g / sqrt(0.1 * g * g)
+            let mut optimizer = RMSProp::new(0.1, 0.9, 1e-8, 0.0, false, 0.0);
+            let mut weight = Parameter::new("weight", row(&START), true);
+            weight.grad = row(&GRADS[0]);
+            optimizer.step(vec![&mut weight]);
+            let step = 0.1 / 0.1f64.sqrt();
+            assert_row(&weight.value, &[1.0 - step, -2.0 + step]);
+        }
+    }
+
+    #[test]
+    fn adagrad_steps_match_the_update_rule() {
+        unsafe {
+            let reference = |mut x: f64, grads: &[f64], decay: f64| {
+                let mut sum = 0.1;
+                for &g in grads {
+                    let g = g + decay * x;
+                    sum += g * g;
+                    x -= 0.5 * g / (sum.sqrt() + 1e-8);
+                }
+                x
+            };
+            let (weight, bias) = run(&mut Adagrad::new(0.5, 0.1, 1e-8, 0.1));
+            assert_row(&weight.value, &expected(|x, g| reference(x, g, 0.1)));
+            assert_row(&bias.value, &expected(|x, g| reference(x, g, 0.0)));
+            // by hand, the bias after one step of 0.5 * 0.5 / sqrt(0.1 + 0.25)
+            let mut optimizer = Adagrad::new(0.5, 0.1, 1e-8, 0.1);
+            let mut bias = Parameter::new("bias", row(&START), false);
+            bias.grad = row(&GRADS[0]);
+            optimizer.step(vec![&mut bias]);
+            assert!((bias.value.at(0, 0) as f64 - (1.0 - 0.25 / 0.35f64.sqrt())).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn adadelta_steps_match_the_update_rule() {
+        unsafe {
+            // rho 0.9 and eps 1e-2, large enough to give visible steps
+            let reference = |mut x: f64, grads: &[f64], decay: f64| {
+                let (mut v, mut u) = (0.0, 0.0f64);
+                for &g in grads {
+                    let g = g + decay * x;
+                    v = 0.9 * v + 0.1 * g * g;
+                    let delta = (u + 1e-2).sqrt() / (v + 1e-2).sqrt() * g;
+                    u = 0.9 * u + 0.1 * delta * delta;
+                    x -= delta;
+                }
+                x
+            };
+            let (weight, bias) = run(&mut Adadelta::new(1.0, 0.9, 1e-2, 0.1));
+            assert_row(&weight.value, &expected(|x, g| reference(x, g, 0.1)));
+            assert_row(&bias.value, &expected(|x, g| reference(x, g, 0.0)));
+            assert!((bias.value.at(0, 0) - START[0]).abs() > 1e-2);
+        }
+    }
+
+    // go = -rate * (g + decay * x), v = momentum * v + go
+    // x += v, or go + momentum * v for Nesterov
+    fn sgd_reference(
+        mut x: f64,
+        grads: &[f64],
+        rate: f64,
+        momentum: f64,
+        decay: f64,
+        nesterov: bool,
+    ) -> f64 {
+        let mut v = 0.0;
+        for &g in grads {
+            let go = -rate * (g + decay * x);
+            v = momentum * v + go;
+            x += if nesterov { go + momentum * v } else { v };
+        }
+        x
+    }
+
+    #[test]
+    fn sgd_and_nesterov_steps_match_the_update_rule() {
+        unsafe {
+            for nesterov in [false, true] {
+                let mut optimizer = if nesterov {
+                    SGD::nesterov(0.1, 0.9, 0.01)
+                } else {
+                    SGD::new(0.1, 0.9, 0.01)
+                };
+                let (weight, bias) = run(&mut optimizer);
+                assert_row(
+                    &weight.value,
+                    &expected(|x, g| sgd_reference(x, g, 0.1, 0.9, 0.01, nesterov)),
+                );
+                assert_row(
+                    &bias.value,
+                    &expected(|x, g| sgd_reference(x, g, 0.1, 0.9, 0.0, nesterov)),
+                );
+            }
+            // by hand, the second Nesterov step of the bias is -0.1 * g2 + 0.9 * v2
+            // with v1 = -0.05 and v2 = 0.9 * v1 - 0.01
+            let (_, bias) = run(&mut SGD::nesterov(0.1, 0.9, 0.0));
+            let first = 1.0 - 0.05 - 0.9 * 0.05;
+            assert!((bias.value.at(0, 0) as f64 - (first - 0.01 + 0.9 * -0.055)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn group_options_override_the_optimizer() {
+        unsafe {
+            let weight_group = GroupOptions {
+                rate_scale: 0.5,
+                decay: Some(0.2),
+                momentum: Some(0.5),
+            };
+            // a group decay applies to the bias too
+            let bias_group = GroupOptions {
+                decay: Some(0.3),
+                ..GroupOptions::default()
+            };
+            for nesterov in [false, true] {
+                let mut optimizer = if nesterov {
+                    SGD::nesterov(0.1, 0.9, 0.01)
+                } else {
+                    SGD::new(0.1, 0.9, 0.01)
+                };
+                let (weight, bias) = run_groups(&mut optimizer, weight_group, bias_group);
+                assert_row(
+                    &weight.value,
+                    &expected(|x, g| sgd_reference(x, g, 0.05, 0.5, 0.2, nesterov)),
+                );
+                assert_row(
+                    &bias.value,
+                    &expected(|x, g| sgd_reference(x, g, 0.1, 0.9, 0.3, nesterov)),
+                );
             }
-            v_weight.clamp(-100.0, 100.0);
-            weight.add(v_weight, true);
 
-            let bias_go = d_bias.mul_with_numeric(-self.rate, false).unwrap();
-            if v_bias.is_null() {
-                *v_bias = bias_go;
+            // the first Adam step moves by the scaled rate, and the bias decays
+            let mut optimizer = Adam::adamw(0.1, 0.9, 0.9, 1e-8, 0.0, false);
+            let mut weight = Parameter::new("weight", row(&START), true);
+            let mut bias = Parameter::new("bias", row(&START), false);
+            weight.group = GroupOptions {
+                rate_scale: 0.5,
+                ..GroupOptions::default()
+            };
+            bias.group = bias_group;
+            weight.grad = row(&GRADS[0]);
+            bias.grad = row(&GRADS[0]);
+            optimizer.step(vec![&mut weight, &mut bias]);
+            assert_row(&weight.value, &[0.95, -1.95]);
+            assert_row(&bias.value, &[0.97 - 0.1, -1.94 + 0.1]);
+        }
+    }
+
+    fn norm(x: &[f64]) -> f64 {
+        x.iter().map(|v| v * v).sum::<f64>().sqrt()
+    }
+
+    // the trust ratios take the norm of the whole parameter, so these step START through
+    // GRADS as one vector, rate 0.1, momentum 0.9, trust 0.5 and eps 1e-8
+    fn lars_reference(decay: f64, trusted: bool) -> Vec<f64> {
+        let mut x: Vec<f64> = START.iter().map(|&v| v as f64).collect();
+        let mut v = [0.0; 2];
+        for grad in GRADS.iter() {
+            let g: Vec<f64> = grad.iter().map(|&v| v as f64).collect();
+            let mut rate = 0.1;
+            if trusted {
+                rate *= 0.5 * norm(&x) / (norm(&g) + decay * norm(&x) + 1e-8);
+            }
+            for j in 0..2 {
+                v[j] = 0.9 * v[j] - rate * (g[j] + decay * x[j]);
+                x[j] += v[j];
+            }
+        }
+        x
+    }
+
+    // rate 0.1, both betas 0.9 and eps 1e-8
+    fn lamb_reference(decay: f64, trusted: bool) -> Vec<f64> {
+        let mut x: Vec<f64> = START.iter().map(|&v| v as f64).collect();
+        let (mut m, mut v) = ([0.0; 2], [0.0; 2]);
+        for (t, grad) in GRADS.iter().enumerate() {
+            let t = t as i32 + 1;
+            let mut update = [0.0; 2];
+            for j in 0..2 {
+                let g = grad[j] as f64;
+                m[j] = 0.9 * m[j] + 0.1 * g;
+                v[j] = 0.9 * v[j] + 0.1 * g * g;
+                let m_hat = m[j] / (1.0 - 0.9f64.powi(t));
+                let v_hat = v[j] / (1.0 - 0.9f64.powi(t));
+                update[j] = m_hat / (v_hat.sqrt() + 1e-8) + decay * x[j];
+            }
+            let ratio = if trusted {
+                norm(&x) / norm(&update)
             } else {
-                v_bias.mul_with_numeric(self.momentum, true);
-                v_bias.add(&bias_go, true);
+                1.0
+            };
+            for j in 0..2 {
+                x[j] -= 0.1 * ratio * update[j];
+            }
+        }
+        x
+    }
+
+    #[test]
+    fn lars_and_lamb_steps_match_the_update_rule() {
+        unsafe {
+            for exclude_bias in [true, false] {
+                let (weight, bias) = run(&mut LARS::new(0.1, 0.9, 0.01, 0.5, 1e-8, exclude_bias));
+                assert_row(&weight.value, &lars_reference(0.01, true));
+                assert_row(&bias.value, &lars_reference(0.0, !exclude_bias));
+
+                let (weight, bias) = run(&mut LAMB::new(0.1, 0.9, 0.9, 1e-8, 0.01, exclude_bias));
+                assert_row(&weight.value, &lamb_reference(0.01, true));
+                assert_row(&bias.value, &lamb_reference(0.0, !exclude_bias));
             }
-            v_bias.clamp(-100.0, 100.0);
-            bias.add(v_bias, true);
+
+            // by hand, without decay the first step of either is rate * trust * |w| long
+            let length = |optimizer: &mut dyn Optimizer| {
+                let mut weight = Parameter::new("weight", row(&START), true);
+                weight.grad = row(&GRADS[0]);
+                optimizer.step(vec![&mut weight]);
+                let step: Vec<f64> = (0..2)
+                    .map(|j| (weight.value.at(0, j) - START[j as usize]) as f64)
+                    .collect();
+                norm(&step)
+            };
+            let w = 5.0f64.sqrt();
+            assert!(
+                (length(&mut LARS::new(0.1, 0.9, 0.0, 0.5, 1e-8, true)) - 0.05 * w).abs() < 1e-5
+            );
+            assert!(
+                (length(&mut LAMB::new(0.1, 0.9, 0.9, 1e-8, 0.0, true)) - 0.1 * w).abs() < 1e-5
+            );
+        }
+    }
+
+    unsafe fn bits(parameters: &[Parameter]) -> Vec<u32> {
+        parameters
+            .iter()
+            .flat_map(|p| (0..2).map(move |j| p.value.at(0, j).to_bits()))
+            .collect()
+    }
+
+    // the state written after one step and read into a fresh optimizer, with other
+    // parameters of the same values, gives the same second step
+    unsafe fn round_trip(name: &str, make: impl Fn() -> Box<dyn Optimizer>) {
+        let path = std::env::temp_dir()
+            .join(format!("tinynet_{}_{}.tnts", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        let mut original = make();
+        // a scheduled rate is part of the state
+        original.set_rate(original.rate() * 0.5);
+        let mut kept = [
+            Parameter::new("weight", row(&START), true),
+            Parameter::new("bias", row(&START), false),
+        ];
+        for parameter in kept.iter_mut() {
+            parameter.grad = row(&GRADS[0]);
+        }
+        original.step(kept.iter_mut().collect());
+
+        let mut w = Writer::new(b"TEST");
+        original.write_state(&kept.iter_mut().collect::<Vec<_>>(), &mut w);
+        w.save(&path).unwrap();
+
+        let mut restored = make();
+        let mut moved = [
+            Parameter::new("weight", kept[0].value.clone(), true),
+            Parameter::new("bias", kept[1].value.clone(), false),
+        ];
+        let mut r = Reader::open(&path, b"TEST").unwrap();
+        restored
+            .read_state(&moved.iter_mut().collect::<Vec<_>>(), &mut r)
+            .unwrap();
+        r.finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(original.rate(), restored.rate());
+
+        for (parameters, optimizer) in [(&mut kept, &mut original), (&mut moved, &mut restored)] {
+            for parameter in parameters.iter_mut() {
+                parameter.grad = row(&GRADS[1]);
+            }
+            optimizer.step(parameters.iter_mut().collect());
+        }
+        assert_eq!(bits(&kept), bits(&moved), "{}", name);
+    }
+
+    #[test]
+    fn optimizer_state_round_trips() {
+        unsafe {
+            round_trip("sgd", || Box::new(SGD::nesterov(0.1, 0.9, 0.01)));
+            round_trip("adam", || {
+                Box::new(Adam::new(0.1, 0.9, 0.9, 1e-8, 0.01, true))
+            });
+            round_trip("adamw", || {
+                Box::new(Adam::adamw(0.1, 0.9, 0.9, 1e-8, 0.01, false))
+            });
+            round_trip("rmsprop", || {
+                Box::new(RMSProp::new(0.1, 0.9, 1e-8, 0.5, true, 0.01))
+            });
+            round_trip("adagrad", || Box::new(Adagrad::new(0.5, 0.1, 1e-8, 0.1)));
+            round_trip("adadelta", || Box::new(Adadelta::new(1.0, 0.9, 1e-2, 0.1)));
+            round_trip("lars", || {
+                Box::new(LARS::new(0.1, 0.9, 0.01, 0.5, 1e-8, true))
+            });
+            round_trip("lamb", || {
+                Box::new(LAMB::new(0.1, 0.9, 0.9, 1e-8, 0.01, true))
+            });
         }
     }
 }
@@ -0,0 +1,101 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::zeros;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// overrides of the optimizer's hyperparameters for one parameter, see Network::set_group
+#[derive(Clone, Copy, PartialEq)]
+pub struct GroupOptions {
+    // multiplies the optimizer's rate, so schedulers still apply
+    pub rate_scale: f32,
+    pub decay: Option<f32>,
+    // used by the optimizers with a momentum term, SGD and RMSProp
+    pub momentum: Option<f32>,
+}
+
+impl Default for GroupOptions {
+    fn default() -> Self {
+        Self {
+            rate_scale: 1.0,
+            decay: None,
+            momentum: None,
+        }
+    }
+}
+
+pub enum ParameterSelector {
+    // parameters of these layers, the head counts as the layer after the last one
+    Layers(Range<usize>),
+    // parameters whose name holds this, e.g. "bias" or "norm"
+    NameContains(String),
+}
+
+pub struct Parameter {
+    pub name: String,
+    pub value: Matrix,
+    pub grad: Matrix,
+    pub requires_grad: bool,
+    // whether the optimizer applies weight decay, off for biases
+    pub decay: bool,
+    pub group: GroupOptions,
+    id: usize,
+}
+
+impl Parameter {
+    pub unsafe fn new(name: &str, value: Matrix, decay: bool) -> Self {
+        let (h, w) = value.shape();
+        Self {
+            name: name.to_string(),
+            value,
+            grad: zeros(h, w),
+            requires_grad: true,
+            decay,
+            group: GroupOptions::default(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    // unique for the lifetime of the process, optimizers key their state on it
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn rate(&self, rate: f32) -> f32 {
+        rate * self.group.rate_scale
+    }
+    // a group decay applies even to the parameters that are off by default
+    pub fn weight_decay(&self, decay: f32) -> f32 {
+        match self.group.decay {
+            Some(decay) => decay,
+            None if self.decay => decay,
+            None => 0.0,
+        }
+    }
+    pub fn momentum(&self, momentum: f32) -> f32 {
+        self.group.momentum.unwrap_or(momentum)
+    }
+
+    // a handle on the same weights for another layer to be built with
+    // every handle keeps its own grad, Network sums them before the optimizer step
+    pub unsafe fn share(&self) -> Self {
+        let (h, w) = self.value.shape();
+        Self {
+            name: self.name.clone(),
+            value: self.value.share(),
+            grad: zeros(h, w),
+            requires_grad: self.requires_grad,
+            decay: self.decay,
+            group: self.group,
+            id: self.id,
+        }
+    }
+}
+
+// names the parameters of a sub layer as `prefix.name`
+pub fn prefix_parameters(parameters: Vec<&mut Parameter>, prefix: &str) {
+    for parameter in parameters {
+        parameter.name = format!("{}.{}", prefix, parameter.name);
+    }
+}
@@ -0,0 +1,864 @@
+use crate::utils::checkpoint::invalid;
+use crate::utils::mat::Matrix;
+use crate::utils::misc::assign;
+use crate::utils::nn_trait::{Layer, LayerConfig};
+use std::collections::HashMap;
+use std::io::Result;
+
+// a row major array read from a file, converted to f32
+pub struct Tensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits >> 15 == 1 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let fraction = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * fraction * 2f32.powi(-24),
+        0x1f if fraction == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + fraction / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+// F32, F64, F16 and BF16 in either byte order
+fn decode(bytes: &[u8], dtype: &str, little_endian: bool) -> Result<Vec<f32>> {
+    let size = match dtype {
+        "F64" => 8,
+        "F32" => 4,
+        "F16" | "BF16" => 2,
+        _ => return Err(invalid(format!("unsupported dtype {}", dtype))),
+    };
+    if !bytes.len().is_multiple_of(size) {
+        return Err(invalid(format!("{} data of {} bytes", dtype, bytes.len())));
+    }
+    Ok(bytes
+        .chunks(size)
+        .map(|x| {
+            let mut x = x.to_vec();
+            if !little_endian {
+                x.reverse();
+            }
+            match dtype {
+                "F64" => f64::from_le_bytes(x.try_into().unwrap()) as f32,
+                "F32" => f32::from_le_bytes(x.try_into().unwrap()),
+                "F16" => f16_to_f32(u16::from_le_bytes(x.try_into().unwrap())),
+                _ => f32::from_bits((u16::from_le_bytes(x.try_into().unwrap()) as u32) << 16),
+            }
+        })
+        .collect())
+}
+
+// just enough JSON for a safetensors header
+enum Json {
+    Null,
+    Bool,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_space(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+    fn expect(&mut self, c: u8) -> Result<()> {
+        self.skip_space();
+        if self.text.get(self.pos) != Some(&c) {
+            return Err(invalid(format!(
+                "broken safetensors header, expected '{}' at {}",
+                c as char, self.pos
+            )));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut ret = String::new();
+        loop {
+            let c = *self
+                .text
+                .get(self.pos)
+                .ok_or_else(|| invalid("broken safetensors header".to_string()))?;
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(ret),
+                b'\\' => {
+                    let e = self.text.get(self.pos).copied().unwrap_or(b' ');
+                    self.pos += 1;
+                    ret.push(match e {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let hex = self
+                                .text
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|x| std::str::from_utf8(x).ok())
+                                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                                .ok_or_else(|| invalid("broken safetensors header".to_string()))?;
+                            self.pos += 4;
+                            char::from_u32(hex).unwrap_or('\u{fffd}')
+                        }
+                        other => other as char,
+                    });
+                }
+                _ => {
+                    // copy a whole utf-8 sequence at once
+                    let start = self.pos - 1;
+                    while self.pos < self.text.len() && self.text[self.pos] & 0xc0 == 0x80 {
+                        self.pos += 1;
+                    }
+                    ret.push_str(&String::from_utf8_lossy(&self.text[start..self.pos]));
+                }
+            }
+        }
+    }
+    fn value(&mut self) -> Result<Json> {
+        self.skip_space();
+        match self.text.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut ret = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(ret));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    ret.push((key, self.value()?));
+                    self.skip_space();
+                    if self.text.get(self.pos) == Some(&b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(b'}')?;
+                        return Ok(Json::Object(ret));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut ret = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(ret));
+                }
+                loop {
+                    ret.push(self.value()?);
+                    self.skip_space();
+                    if self.text.get(self.pos) == Some(&b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(b']')?;
+                        return Ok(Json::Array(ret));
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.text.len() && !b",]} \t\r\n".contains(&self.text[self.pos]) {
+                    self.pos += 1;
+                }
+                match &self.text[start..self.pos] {
+                    b"null" => Ok(Json::Null),
+                    b"true" | b"false" => Ok(Json::Bool),
+                    x => std::str::from_utf8(x)
+                        .ok()
+                        .and_then(|x| x.parse().ok())
+                        .map(Json::Number)
+                        .ok_or_else(|| invalid(format!("broken safetensors header at {}", start))),
+                }
+            }
+            None => Err(invalid("safetensors header ends early".to_string())),
+        }
+    }
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(x) => x.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    fn numbers(&self) -> Option<Vec<usize>> {
+        match self {
+            Json::Array(x) => x
+                .iter()
+                .map(|v| match v {
+                    Json::Number(n) if *n >= 0.0 => Some(*n as usize),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+// u64 header length, a JSON header of name => dtype, shape and data_offsets, then the data
+pub fn read_safetensors(path: &str) -> Result<HashMap<String, Tensor>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() < 8 {
+        return Err(invalid(format!("{} is not a safetensors file", path)));
+    }
+    let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    if header_len > bytes.len() - 8 {
+        return Err(invalid(format!("{} is not a safetensors file", path)));
+    }
+    let data = &bytes[8 + header_len..];
+    let mut parser = JsonParser {
+        text: &bytes[8..8 + header_len],
+        pos: 0,
+    };
+    let Json::Object(entries) = parser.value()? else {
+        return Err(invalid(format!("{} has no tensor table", path)));
+    };
+    let mut ret = HashMap::new();
+    for (name, entry) in entries.iter() {
+        if name == "__metadata__" {
+            continue;
+        }
+        let (Some(Json::String(dtype)), Some(shape), Some(offsets)) = (
+            entry.get("dtype"),
+            entry.get("shape").and_then(|x| x.numbers()),
+            entry.get("data_offsets").and_then(|x| x.numbers()),
+        ) else {
+            return Err(invalid(format!("{} has a broken entry for {}", path, name)));
+        };
+        if offsets.len() != 2 || offsets[0] > offsets[1] || offsets[1] > data.len() {
+            return Err(invalid(format!("{} has broken offsets for {}", path, name)));
+        }
+        let values = decode(&data[offsets[0]..offsets[1]], dtype, true)?;
+        if values.len() != shape.iter().product::<usize>() {
+            return Err(invalid(format!("{} has the wrong size for {}", path, name)));
+        }
+        ret.insert(
+            name.clone(),
+            Tensor {
+                shape,
+                data: values,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+// NPY version 1 to 3, float arrays in either byte order and either memory order
+pub fn parse_npy(bytes: &[u8]) -> Result<Tensor> {
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err(invalid("not an npy array".to_string()));
+    }
+    let (header_len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        v => return Err(invalid(format!("unsupported npy version {}", v))),
+    };
+    if start + header_len > bytes.len() {
+        return Err(invalid("npy header ends early".to_string()));
+    }
+    // a python dict literal, {'descr': '<f4', 'fortran_order': False, 'shape': (3, 4), }
+    let header = String::from_utf8_lossy(&bytes[start..start + header_len]);
+    let field = |key: &str| -> Option<&str> {
+        let at = header.find(&format!("'{}'", key))? + key.len() + 2;
+        Some(header[at..].trim_start().strip_prefix(':')?.trim_start())
+    };
+    let descr = field("descr")
+        .and_then(|x| x.strip_prefix('\''))
+        .and_then(|x| x.split('\'').next())
+        .ok_or_else(|| invalid("npy header has no descr".to_string()))?;
+    let fortran_order = field("fortran_order")
+        .map(|x| x.starts_with("True"))
+        .ok_or_else(|| invalid("npy header has no fortran_order".to_string()))?;
+    let shape: Vec<usize> = field("shape")
+        .and_then(|x| x.strip_prefix('('))
+        .and_then(|x| x.split(')').next())
+        .map(|x| {
+            x.split(',')
+                .map(|d| d.trim())
+                .filter(|d| !d.is_empty())
+                .map(|d| d.parse().ok())
+                .collect::<Option<Vec<usize>>>()
+        })
+        .and_then(|x| x)
+        .ok_or_else(|| invalid("npy header has no shape".to_string()))?;
+
+    let little_endian = !descr.starts_with('>');
+    let dtype = match &descr[descr.len().min(1)..] {
+        "f2" => "F16",
+        "f4" => "F32",
+        "f8" => "F64",
+        _ => return Err(invalid(format!("unsupported npy dtype {}", descr))),
+    };
+    let values = decode(&bytes[start + header_len..], dtype, little_endian)?;
+    if values.len() != shape.iter().product::<usize>() {
+        return Err(invalid("npy data does not match its shape".to_string()));
+    }
+    if !fortran_order || shape.len() < 2 {
+        return Ok(Tensor {
+            shape,
+            data: values,
+        });
+    }
+    // column major, the first index changes fastest
+    let mut data = vec![0f32; values.len()];
+    let mut index = vec![0usize; shape.len()];
+    for v in values {
+        let row_major = index
+            .iter()
+            .zip(shape.iter())
+            .fold(0, |a, (i, d)| a * d + i);
+        data[row_major] = v;
+        for (i, d) in index.iter_mut().zip(shape.iter()) {
+            *i += 1;
+            if *i < *d {
+                break;
+            }
+            *i = 0;
+        }
+    }
+    Ok(Tensor { shape, data })
+}
+
+pub fn read_npy(path: &str) -> Result<Tensor> {
+    parse_npy(&std::fs::read(path)?)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+}
+
+// a zip of npy arrays as written by numpy.savez or savez_compressed, named without `.npy`
+pub fn read_npz(path: &str) -> Result<HashMap<String, Tensor>> {
+    let bytes = std::fs::read(path)?;
+    let broken = || invalid(format!("{} is not a readable npz file", path));
+    // the end of central directory record, 22 bytes plus a comment of up to 64k
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .take(22 + 0xffff)
+        .find(|&at| bytes[at..at + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .ok_or_else(broken)?;
+    let count = u16_at(&bytes, end + 10);
+    let mut at = u32_at(&bytes, end + 16);
+    let mut ret = HashMap::new();
+    for _ in 0..count {
+        if at + 46 > bytes.len() || bytes[at..at + 4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(broken());
+        }
+        let method = u16_at(&bytes, at + 10);
+        let compressed = u32_at(&bytes, at + 20);
+        let name_len = u16_at(&bytes, at + 28);
+        let extra_len = u16_at(&bytes, at + 30);
+        let comment_len = u16_at(&bytes, at + 32);
+        let local = u32_at(&bytes, at + 42);
+        if compressed == 0xffffffff || local == 0xffffffff {
+            return Err(invalid(format!(
+                "{} needs zip64, which is not supported",
+                path
+            )));
+        }
+        let name = bytes.get(at + 46..at + 46 + name_len).ok_or_else(broken)?;
+        let name = String::from_utf8_lossy(name).to_string();
+        at += 46 + name_len + extra_len + comment_len;
+
+        if local + 30 > bytes.len() {
+            return Err(broken());
+        }
+        let start = local + 30 + u16_at(&bytes, local + 26) + u16_at(&bytes, local + 28);
+        let raw = bytes.get(start..start + compressed).ok_or_else(broken)?;
+        let array = match method {
+            0 => parse_npy(raw)?,
+            8 => parse_npy(&miniz_oxide::inflate::decompress_to_vec(raw).map_err(|_| broken())?)?,
+            _ => return Err(invalid(format!("{} uses zip method {}", path, method))),
+        };
+        ret.insert(name.trim_end_matches(".npy").to_string(), array);
+    }
+    Ok(ret)
+}
+
+// the order of the features going into a layer
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Input {
+    Flat,
+    // channels, rows and cols of an HWC image
+    Image(usize, usize, usize),
+    // an image of this many features went through a layer that is not followed
+    Unknown(usize),
+}
+
+fn image_inputs(configs: &[LayerConfig]) -> Vec<Input> {
+    let get = |config: &LayerConfig, name: &str| {
+        config
+            .hyperparameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| *value)
+            .unwrap_or(0)
+    };
+    let mut ret = Vec::new();
+    let mut image = Input::Flat;
+    for config in configs {
+        let input = match config.kind.as_str() {
+            "Conv3x3" | "MaxPool2x2" | "Upsample" | "PatchEmbedding" => Input::Image(
+                get(config, "in_channels"),
+                get(config, "im_row"),
+                get(config, "im_col"),
+            ),
+            _ => image,
+        };
+        ret.push(input);
+        image = match (config.kind.as_str(), input) {
+            ("Conv3x3", Input::Image(_, h, w)) => {
+                let (stride, padding) = (get(config, "stride"), get(config, "padding"));
+                Input::Image(
+                    get(config, "out_channels"),
+                    (h + 2 * padding - 3) / stride + 1,
+                    (w + 2 * padding - 3) / stride + 1,
+                )
+            }
+            ("MaxPool2x2", Input::Image(c, h, w)) => Input::Image(c, h.div_ceil(2), w.div_ceil(2)),
+            ("Upsample", Input::Image(c, h, w)) => {
+                let scale = get(config, "scale");
+                Input::Image(c, h * scale, w * scale)
+            }
+            // feature-wise, the order stays
+            ("ReluLayer" | "LayerNorm", _) => input,
+            // these produce their own features or sequences
+            (
+                "LinearLayer"
+                | "PatchEmbedding"
+                | "Rnn"
+                | "Lstm"
+                | "Gru"
+                | "MultiHeadAttention"
+                | "TransformerEncoderLayer",
+                _,
+            ) => Input::Flat,
+            (_, Input::Image(c, h, w)) => Input::Unknown(c * h * w),
+            (_, input) => input,
+        };
+    }
+    ret
+}
+
+// a PyTorch tensor in TinyNet's layout for this parameter
+// conv kernels [out, in, 3, 3] => (9 * in) * out with rows (ky * 3 + kx) * in + c
+// linear weights [out, in] => in * out, or as they are for a transposed layer
+// vectors [n] => 1 * n
+// the inputs of a linear layer or layer norm that follows an image are reordered from CHW to HWC
+fn convert(
+    tensor: &Tensor,
+    config: &LayerConfig,
+    name: &str,
+    target: (usize, usize),
+    input: Input,
+) -> Option<Vec<f32>> {
+    let (h, w) = target;
+    let shape = tensor.shape.as_slice();
+    let data = &tensor.data;
+    // TinyNet feature of every PyTorch input feature
+    let feature = |i: usize, n: usize| match input {
+        Input::Image(c, ih, iw) if c * ih * iw == n => {
+            let (ch, pixel) = (i / (ih * iw), i % (ih * iw));
+            pixel * c + ch
+        }
+        _ => i,
+    };
+    match (config.kind.as_str(), name, shape) {
+        ("LayerNorm", _, &[n]) if h == 1 && n == w => {
+            let mut ret = vec![0f32; n];
+            for i in 0..n {
+                ret[feature(i, n)] = data[i];
+            }
+            Some(ret)
+        }
+        (_, _, &[n]) if h == 1 && n == w => Some(data.clone()),
+        ("Conv3x3", "weight", &[out, c, 3, 3]) if out == w && 9 * c == h => {
+            let mut ret = vec![0f32; h * w];
+            for o in 0..out {
+                for ch in 0..c {
+                    for k in 0..9 {
+                        ret[(k * c + ch) * w + o] = data[(o * c + ch) * 9 + k];
+                    }
+                }
+            }
+            Some(ret)
+        }
+        ("LinearLayer", "weight", &[out, n]) => {
+            let transpose = config
+                .hyperparameters
+                .iter()
+                .any(|(key, value)| key == "transpose" && *value == 1);
+            if transpose && (h, w) == (out, n) {
+                let mut ret = vec![0f32; h * w];
+                for o in 0..out {
+                    for i in 0..n {
+                        ret[o * w + feature(i, n)] = data[o * n + i];
+                    }
+                }
+                Some(ret)
+            } else if !transpose && (h, w) == (n, out) {
+                let mut ret = vec![0f32; h * w];
+                for o in 0..out {
+                    for i in 0..n {
+                        ret[feature(i, n) * w + o] = data[o * n + i];
+                    }
+                }
+                Some(ret)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// fills the parameters of the given layers from tensors named `source.parameter name`,
+// e.g. (0, "features.0") reads features.0.weight and features.0.bias into layer 0
+// nothing is written unless every parameter of those layers converts, returns how many did
+pub unsafe fn load_tensors(
+    layers: &mut [Box<dyn Layer>],
+    tensors: &HashMap<String, Tensor>,
+    sources: &[(usize, &str)],
+) -> Result<usize> {
+    let configs: Vec<LayerConfig> = layers.iter().map(|layer| layer.config()).collect();
+    let images = image_inputs(&configs);
+    let mut values = Vec::new();
+    for &(idx, source) in sources {
+        let Some(layer) = layers.get_mut(idx) else {
+            return Err(invalid(format!("the network has no layer {}", idx)));
+        };
+        for parameter in layer.parameters() {
+            let key = format!("{}.{}", source, parameter.name);
+            let tensor = tensors
+                .get(&key)
+                .ok_or_else(|| invalid(format!("no tensor {} for layer {}", key, idx)))?;
+            if let Input::Unknown(n) = images[idx] {
+                if tensor.shape.contains(&n) {
+                    return Err(invalid(format!(
+                        "layer {} ({}) takes an image through a layer whose feature order is unknown",
+                        idx, configs[idx].kind
+                    )));
+                }
+            }
+            let (h, w) = parameter.value.shape();
+            let data = convert(tensor, &configs[idx], &parameter.name, (h, w), images[idx])
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "tensor {} {:?} does not fit {} of layer {} ({}), {} * {}",
+                        key, tensor.shape, parameter.name, idx, configs[idx].kind, h, w
+                    ))
+                })?;
+            let value = Matrix::new(h, w);
+            for i in 0..h {
+                for j in 0..w {
+                    *value.row_at(i as isize).add(j) = data[i * w + j];
+                }
+            }
+            values.push((idx, parameter.name.clone(), value));
+        }
+    }
+    let count = values.len();
+    for (idx, name, value) in values {
+        for parameter in layers[idx].parameters() {
+            if parameter.name == name {
+                assign(&parameter.value, &value);
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::conv3x3::Conv3x3;
+    use crate::utils::layernorm::LayerNorm;
+    use crate::utils::linear::LinearLayer;
+    use crate::utils::misc::rand_next;
+    use crate::utils::relu::ReluLayer;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("tinynet_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn npy(descr: &str, fortran_order: bool, shape: &str, data: &[u8]) -> Vec<u8> {
+        let header = format!(
+            "{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}\n",
+            descr,
+            if fortran_order { "True" } else { "False" },
+            shape
+        );
+        let mut ret = b"\x93NUMPY\x01\x00".to_vec();
+        ret.extend((header.len() as u16).to_le_bytes());
+        ret.extend(header.as_bytes());
+        ret.extend(data);
+        ret
+    }
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn safetensors(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut ret = (header.len() as u64).to_le_bytes().to_vec();
+        ret.extend(header.as_bytes());
+        ret.extend(data);
+        ret
+    }
+
+    // a zip of stored entries, as numpy.savez writes them
+    fn zip(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data) in entries {
+            let local = ret.len() as u32;
+            ret.extend([0x50, 0x4b, 0x03, 0x04]);
+            ret.extend([0u8; 14]);
+            ret.extend((data.len() as u32).to_le_bytes());
+            ret.extend((data.len() as u32).to_le_bytes());
+            ret.extend((name.len() as u16).to_le_bytes());
+            ret.extend([0u8; 2]);
+            ret.extend(name.as_bytes());
+            ret.extend(data);
+
+            directory.extend([0x50, 0x4b, 0x01, 0x02]);
+            directory.extend([0u8; 16]);
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0u8; 12]);
+            directory.extend(local.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let offset = ret.len() as u32;
+        ret.extend(&directory);
+        ret.extend([0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        ret.extend((entries.len() as u16).to_le_bytes());
+        ret.extend((entries.len() as u16).to_le_bytes());
+        ret.extend((directory.len() as u32).to_le_bytes());
+        ret.extend(offset.to_le_bytes());
+        ret.extend([0u8; 2]);
+        ret
+    }
+
+    #[test]
+    fn npy_orders_and_dtypes() {
+        let values = [0.0f32, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let tensor = parse_npy(&npy("<f4", false, "(2, 3)", &f32_bytes(&values))).unwrap();
+        assert_eq!(tensor.shape, vec![2, 3]);
+        assert_eq!(tensor.data, values.to_vec());
+
+        // column major [[0, 1, 2], [3, 4, 5]] is 0 3 1 4 2 5
+        let stored = [0.0f32, 3.0, 1.0, 4.0, 2.0, 5.0];
+        let tensor = parse_npy(&npy("<f4", true, "(2, 3)", &f32_bytes(&stored))).unwrap();
+        assert_eq!(tensor.data, values.to_vec());
+
+        let big_endian: Vec<u8> = values
+            .iter()
+            .flat_map(|x| (*x as f64).to_be_bytes())
+            .collect();
+        let tensor = parse_npy(&npy(">f8", false, "(6,)", &big_endian)).unwrap();
+        assert_eq!(tensor.shape, vec![6]);
+        assert_eq!(tensor.data, values.to_vec());
+
+        assert!(parse_npy(&npy("<i4", false, "(6,)", &f32_bytes(&values))).is_err());
+        assert!(parse_npy(&npy("<f4", false, "(2, 4)", &f32_bytes(&values))).is_err());
+    }
+
+    #[test]
+    fn safetensors_round_trip() {
+        let path = temp_path("round_trip.safetensors");
+        let header = r#"{"__metadata__": {"format": "pt"}, "fc.weight": {"dtype": "F32", "shape": [2, 2], "data_offsets": [0, 16]}, "fc.bias": {"dtype": "BF16", "shape": [2], "data_offsets": [16, 20]}}"#;
+        let mut data = f32_bytes(&[1.0, -2.0, 0.5, 4.0]);
+        data.extend([0x80, 0x3f, 0x00, 0xc0]);
+        std::fs::write(&path, safetensors(header, &data)).unwrap();
+        let tensors = read_safetensors(&path).unwrap();
+        assert_eq!(tensors["fc.weight"].shape, vec![2, 2]);
+        assert_eq!(tensors["fc.weight"].data, vec![1.0, -2.0, 0.5, 4.0]);
+        assert_eq!(tensors["fc.bias"].data, vec![1.0, -2.0]);
+
+        // a header cut inside an escape or data past the end are errors, not panics
+        std::fs::write(&path, safetensors(r#"{"fc.w\u00"#, &data)).unwrap();
+        assert!(read_safetensors(&path).is_err());
+        let header = r#"{"fc.bias": {"dtype": "F32", "shape": [2], "data_offsets": [0, 64]}}"#;
+        std::fs::write(&path, safetensors(header, &data)).unwrap();
+        assert!(read_safetensors(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        let path = temp_path("round_trip.npz");
+        let weight = npy("<f4", false, "(2, 2)", &f32_bytes(&[1.0, 2.0, 3.0, 4.0]));
+        let bias = npy("<f4", false, "(2,)", &f32_bytes(&[5.0, 6.0]));
+        let bytes = zip(&[("fc.weight.npy", weight), ("fc.bias.npy", bias)]);
+        std::fs::write(&path, &bytes).unwrap();
+        let tensors = read_npz(&path).unwrap();
+        assert_eq!(tensors["fc.weight"].data, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(tensors["fc.bias"].data, vec![5.0, 6.0]);
+
+        // a central directory entry whose name runs past the end of the file
+        let mut broken = bytes.clone();
+        let at = broken.len() - 22 - 46 - "fc.bias.npy".len();
+        broken[at + 28..at + 30].copy_from_slice(&1000u16.to_le_bytes());
+        std::fs::write(&path, &broken).unwrap();
+        assert!(read_npz(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    struct Identity;
+
+    impl Layer for Identity {
+        fn forward(&mut self, input: Matrix) -> Matrix {
+            input
+        }
+        fn backward(&mut self, dLoss: Matrix) -> Matrix {
+            dLoss
+        }
+        fn config(&self) -> LayerConfig {
+            LayerConfig::new("Identity", &[])
+        }
+    }
+
+    fn random(len: usize, seed: &mut u32) -> Vec<f32> {
+        (0..len)
+            .map(|_| (rand_next(seed) % 2000) as f32 / 1000.0 - 1.0)
+            .collect()
+    }
+
+    fn tensor(shape: &[usize], seed: &mut u32) -> Tensor {
+        Tensor {
+            shape: shape.to_vec(),
+            data: random(shape.iter().product(), seed),
+        }
+    }
+
+    // conv, relu, flatten, layer norm and linear on a CHW image, the way PyTorch runs them
+    fn reference(tensors: &HashMap<String, Tensor>, image: &[f32]) -> Vec<f32> {
+        let (c, out, h, w) = (2, 3, 4, 4);
+        let weight = &tensors["conv.weight"].data;
+        let mut x = tensors["conv.bias"]
+            .data
+            .iter()
+            .flat_map(|&b| std::iter::repeat_n(b, h * w))
+            .collect::<Vec<f32>>();
+        for o in 0..out {
+            for y in 0..h {
+                for x_ in 0..w {
+                    for ch in 0..c {
+                        for ky in 0..3 {
+                            for kx in 0..3 {
+                                let (iy, ix) = (y + ky, x_ + kx);
+                                if iy < 1 || ix < 1 || iy > h || ix > w {
+                                    continue;
+                                }
+                                x[(o * h + y) * w + x_] += weight[((o * c + ch) * 3 + ky) * 3 + kx]
+                                    * image[(ch * h + iy - 1) * w + ix - 1];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        x.iter_mut().for_each(|v| *v = v.max(0.0));
+        let n = x.len() as f32;
+        let mean = x.iter().sum::<f32>() / n;
+        let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+        let (gamma, beta) = (&tensors["norm.weight"].data, &tensors["norm.bias"].data);
+        for (i, v) in x.iter_mut().enumerate() {
+            *v = (*v - mean) / (var + 1e-5).sqrt() * gamma[i] + beta[i];
+        }
+        let (weight, bias) = (&tensors["fc.weight"].data, &tensors["fc.bias"].data);
+        (0..5)
+            .map(|o| bias[o] + (0..48).map(|i| weight[o * 48 + i] * x[i]).sum::<f32>())
+            .collect()
+    }
+
+    #[test]
+    fn pytorch_layouts_give_the_same_output() {
+        unsafe {
+            let mut seed = 11u32;
+            let tensors: HashMap<String, Tensor> = [
+                ("conv.weight", tensor(&[3, 2, 3, 3], &mut seed)),
+                ("conv.bias", tensor(&[3], &mut seed)),
+                ("norm.weight", tensor(&[48], &mut seed)),
+                ("norm.bias", tensor(&[48], &mut seed)),
+                ("fc.weight", tensor(&[5, 48], &mut seed)),
+                ("fc.bias", tensor(&[5], &mut seed)),
+            ]
+            .into_iter()
+            .map(|(name, tensor)| (name.to_string(), tensor))
+            .collect();
+            let mut layers: Vec<Box<dyn Layer>> = vec![
+                Box::new(Conv3x3::new(2, 3, 4, 4, 1, 1)),
+                Box::new(ReluLayer::new()),
+                Box::new(LayerNorm::new(48)),
+                Box::new(LinearLayer::new(48, 5)),
+            ];
+            let sources = [(0, "conv"), (2, "norm"), (3, "fc")];
+            assert_eq!(load_tensors(&mut layers, &tensors, &sources).unwrap(), 6);
+
+            let image = random(32, &mut seed);
+            let x = Matrix::new(1, 32);
+            for ch in 0..2 {
+                for pixel in 0..16 {
+                    *x.row_at(0).add(pixel * 2 + ch) = image[ch * 16 + pixel];
+                }
+            }
+            let y = layers.iter_mut().fold(x, |x, layer| layer.forward(x));
+            for (o, expected) in reference(&tensors, &image).iter().enumerate() {
+                assert!((y.at(0, o as isize) - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_feature_order_is_refused() {
+        unsafe {
+            let mut seed = 11u32;
+            let tensors: HashMap<String, Tensor> = [
+                ("fc.weight", tensor(&[5, 48], &mut seed)),
+                ("fc.bias", tensor(&[5], &mut seed)),
+            ]
+            .into_iter()
+            .map(|(name, tensor)| (name.to_string(), tensor))
+            .collect();
+            let mut layers: Vec<Box<dyn Layer>> = vec![
+                Box::new(Conv3x3::new(2, 3, 4, 4, 1, 1)),
+                Box::new(Identity),
+                Box::new(LinearLayer::new(48, 5)),
+            ];
+            let before = layers[2].parameters()[0].value.clone();
+            assert!(load_tensors(&mut layers, &tensors, &[(2, "fc")]).is_err());
+            let after = &layers[2].parameters()[0].value;
+            assert_eq!(before.at(0, 0), after.at(0, 0));
+        }
+    }
+}
@@ -0,0 +1,686 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::{copy_cols, slice_cols, sum_rows, zeros};
+use crate::utils::nn_trait;
+use crate::utils::parameter::Parameter;
+use rayon::prelude::*;
+
+// input is B*TF (time major inside a row), output is B*TH or B*H
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+unsafe fn initial_state(state: &Matrix, stateful: bool, h: usize, w: usize) -> Matrix {
+    if stateful && !state.is_null() && state.number_of_row() == h {
+        state.clone()
+    } else {
+        zeros(h, w)
+    }
+}
+
+// B*TF, B*H => B*(F+H)
+unsafe fn concat_step(input: &Matrix, t: usize, in_features: usize, state: &Matrix) -> Matrix {
+    let (h, hidden) = state.shape();
+    let ret = Matrix::new(h, in_features + hidden);
+    copy_cols(input, t * in_features, &ret, 0, in_features);
+    copy_cols(state, 0, &ret, in_features, hidden);
+    ret
+}
+
+unsafe fn collect_output(states: &[Matrix], return_sequences: bool) -> Matrix {
+    let last = states.last().unwrap();
+    if !return_sequences {
+        return last.clone();
+    }
+    let (h, hidden) = last.shape();
+    let ret = Matrix::new(h, hidden * states.len());
+    for (t, state) in states.iter().enumerate() {
+        copy_cols(state, 0, &ret, t * hidden, hidden);
+    }
+    ret
+}
+
+unsafe fn output_grad(
+    dLoss: &Matrix,
+    t: usize,
+    seq_len: usize,
+    hidden: usize,
+    return_sequences: bool,
+) -> Option<Matrix> {
+    if return_sequences {
+        Some(slice_cols(dLoss, t * hidden, hidden))
+    } else if t + 1 == seq_len {
+        Some(dLoss.clone())
+    } else {
+        None
+    }
+}
+
+// truncated BPTT, the gradient is not carried from step t into t-1 at chunk boundaries
+fn cut_gradient(t: usize, bptt_steps: usize) -> bool {
+    bptt_steps != 0 && t.is_multiple_of(bptt_steps)
+}
+
+fn check_input(input: &Matrix, seq_len: usize, in_features: usize) {
+    if input.number_of_col() != seq_len * in_features {
+        panic!("call recurrent layer with unmatched input shape");
+    }
+}
+
+pub struct Rnn {
+    pub in_features: usize,
+    pub hidden_size: usize,
+    pub seq_len: usize,
+    pub return_sequences: bool,
+    pub stateful: bool,
+    pub bptt_steps: usize,
+
+    // (F+H)*H
+    pub weight: Parameter,
+    pub bias: Parameter,
+
+    pub hidden: Matrix,
+
+    last_xh: Vec<Matrix>,
+    last_h: Vec<Matrix>,
+}
+
+impl Rnn {
+    pub unsafe fn new(
+        in_features: usize,
+        hidden_size: usize,
+        seq_len: usize,
+        return_sequences: bool,
+        stateful: bool,
+        bptt_steps: usize,
+    ) -> Self {
+        let weight = Matrix::new(in_features + hidden_size, hidden_size);
+        let bias = Matrix::new(1, hidden_size);
+        weight.normal_init();
+        bias.normal_init();
+        Self {
+            in_features,
+            hidden_size,
+            seq_len,
+            return_sequences,
+            stateful,
+            bptt_steps,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            hidden: Matrix::null(),
+            last_xh: Vec::new(),
+            last_h: Vec::new(),
+        }
+    }
+    pub unsafe fn reset_state(&mut self) {
+        self.hidden = Matrix::null();
+    }
+}
+
+impl nn_trait::Layer for Rnn {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            check_input(&input, self.seq_len, self.in_features);
+            let h = input.number_of_row();
+            let mut state = initial_state(&self.hidden, self.stateful, h, self.hidden_size);
+            self.last_xh.clear();
+            self.last_h.clear();
+            for t in 0..self.seq_len {
+                let xh = concat_step(&input, t, self.in_features, &state);
+                let now = xh.mul(&self.weight.value);
+                now.add_with_vector(&self.bias.value, true);
+                let w = self.hidden_size;
+                (0..h).into_par_iter().for_each(|idx| {
+                    let row = now.row_at(idx as isize);
+                    for j in 0..w {
+                        *row.add(j) = (*row.add(j)).tanh();
+                    }
+                });
+                state = now.clone();
+                self.last_xh.push(xh);
+                self.last_h.push(now);
+            }
+            if self.stateful {
+                self.hidden = state;
+            }
+            collect_output(&self.last_h, self.return_sequences)
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let in_features = self.in_features;
+            let hidden = self.hidden_size;
+            let ret = Matrix::new(h, self.seq_len * in_features);
+            let wt = self.weight.value.T();
+
+            let mut dh_next = zeros(h, hidden);
+            for t in (0..self.seq_len).rev() {
+                let dh = dh_next;
+                if let Some(grad) =
+                    output_grad(&dLoss, t, self.seq_len, hidden, self.return_sequences)
+                {
+                    dh.add(&grad, true);
+                }
+                let state = &self.last_h[t];
+                (0..h).into_par_iter().for_each(|idx| {
+                    let src = state.row_at(idx as isize);
+                    let dst = dh.row_at(idx as isize);
+                    for j in 0..hidden {
+                        let y = *src.add(j);
+                        *dst.add(j) *= 1.0 - y * y;
+                    }
+                });
+                self.weight.grad.add(&self.last_xh[t].T().mul(&dh), true);
+                self.bias.grad.add(&sum_rows(&dh), true);
+
+                let dxh = dh.mul(&wt);
+                copy_cols(&dxh, 0, &ret, t * in_features, in_features);
+                dh_next = if cut_gradient(t, self.bptt_steps) {
+                    zeros(h, hidden)
+                } else {
+                    slice_cols(&dxh, in_features, hidden)
+                };
+            }
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "Rnn",
+            &[
+                ("in_features", self.in_features),
+                ("hidden_size", self.hidden_size),
+                ("seq_len", self.seq_len),
+                ("return_sequences", self.return_sequences as usize),
+                ("stateful", self.stateful as usize),
+                ("bptt_steps", self.bptt_steps),
+            ],
+        )
+    }
+}
+
+pub struct Lstm {
+    pub in_features: usize,
+    pub hidden_size: usize,
+    pub seq_len: usize,
+    pub return_sequences: bool,
+    pub stateful: bool,
+    pub bptt_steps: usize,
+
+    // (F+H)*4H, gates ordered as [input, forget, cell, output]
+    pub weight: Parameter,
+    pub bias: Parameter,
+
+    pub hidden: Matrix,
+    pub cell: Matrix,
+
+    last_xh: Vec<Matrix>,
+    last_gates: Vec<Matrix>,
+    // c_0 .. c_T
+    last_c: Vec<Matrix>,
+    last_h: Vec<Matrix>,
+}
+
+impl Lstm {
+    pub unsafe fn new(
+        in_features: usize,
+        hidden_size: usize,
+        seq_len: usize,
+        return_sequences: bool,
+        stateful: bool,
+        bptt_steps: usize,
+    ) -> Self {
+        let weight = Matrix::new(in_features + hidden_size, 4 * hidden_size);
+        let bias = Matrix::new(1, 4 * hidden_size);
+        weight.normal_init();
+        bias.normal_init();
+        Self {
+            in_features,
+            hidden_size,
+            seq_len,
+            return_sequences,
+            stateful,
+            bptt_steps,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            hidden: Matrix::null(),
+            cell: Matrix::null(),
+            last_xh: Vec::new(),
+            last_gates: Vec::new(),
+            last_c: Vec::new(),
+            last_h: Vec::new(),
+        }
+    }
+    pub unsafe fn reset_state(&mut self) {
+        self.hidden = Matrix::null();
+        self.cell = Matrix::null();
+    }
+}
+
+impl nn_trait::Layer for Lstm {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            check_input(&input, self.seq_len, self.in_features);
+            let h = input.number_of_row();
+            let hidden = self.hidden_size;
+            let mut state = initial_state(&self.hidden, self.stateful, h, hidden);
+            self.last_xh.clear();
+            self.last_gates.clear();
+            self.last_c.clear();
+            self.last_h.clear();
+            self.last_c
+                .push(initial_state(&self.cell, self.stateful, h, hidden));
+            for t in 0..self.seq_len {
+                let xh = concat_step(&input, t, self.in_features, &state);
+                let gates = xh.mul(&self.weight.value);
+                gates.add_with_vector(&self.bias.value, true);
+                let c_prev = self.last_c.last().unwrap();
+                let c = Matrix::new(h, hidden);
+                let now = Matrix::new(h, hidden);
+                (0..h).into_par_iter().for_each(|idx| {
+                    let g = gates.row_at(idx as isize);
+                    let cp = c_prev.row_at(idx as isize);
+                    let cn = c.row_at(idx as isize);
+                    let hn = now.row_at(idx as isize);
+                    for j in 0..hidden {
+                        let i = sigmoid(*g.add(j));
+                        let f = sigmoid(*g.add(hidden + j));
+                        let cc = (*g.add(2 * hidden + j)).tanh();
+                        let o = sigmoid(*g.add(3 * hidden + j));
+                        *g.add(j) = i;
+                        *g.add(hidden + j) = f;
+                        *g.add(2 * hidden + j) = cc;
+                        *g.add(3 * hidden + j) = o;
+                        let val = f * *cp.add(j) + i * cc;
+                        *cn.add(j) = val;
+                        *hn.add(j) = o * val.tanh();
+                    }
+                });
+                state = now.clone();
+                self.last_xh.push(xh);
+                self.last_gates.push(gates);
+                self.last_c.push(c);
+                self.last_h.push(now);
+            }
+            if self.stateful {
+                self.hidden = state;
+                self.cell = self.last_c.last().unwrap().clone();
+            }
+            collect_output(&self.last_h, self.return_sequences)
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let in_features = self.in_features;
+            let hidden = self.hidden_size;
+            let ret = Matrix::new(h, self.seq_len * in_features);
+            let wt = self.weight.value.T();
+
+            let mut dh_next = zeros(h, hidden);
+            let mut dc_next = zeros(h, hidden);
+            for t in (0..self.seq_len).rev() {
+                let dh = dh_next;
+                if let Some(grad) =
+                    output_grad(&dLoss, t, self.seq_len, hidden, self.return_sequences)
+                {
+                    dh.add(&grad, true);
+                }
+                let gates = &self.last_gates[t];
+                let c = &self.last_c[t + 1];
+                let c_prev = &self.last_c[t];
+                let dz = Matrix::new(h, 4 * hidden);
+                let dc_prev = Matrix::new(h, hidden);
+                (0..h).into_par_iter().for_each(|idx| {
+                    let g = gates.row_at(idx as isize);
+                    let cn = c.row_at(idx as isize);
+                    let cp = c_prev.row_at(idx as isize);
+                    let dhn = dh.row_at(idx as isize);
+                    let dcn = dc_next.row_at(idx as isize);
+                    let dg = dz.row_at(idx as isize);
+                    let dcp = dc_prev.row_at(idx as isize);
+                    for j in 0..hidden {
+                        let i = *g.add(j);
+                        let f = *g.add(hidden + j);
+                        let cc = *g.add(2 * hidden + j);
+                        let o = *g.add(3 * hidden + j);
+                        let tc = (*cn.add(j)).tanh();
+                        let d = *dhn.add(j);
+                        let dc = d * o * (1.0 - tc * tc) + *dcn.add(j);
+                        *dg.add(j) = dc * cc * i * (1.0 - i);
+                        *dg.add(hidden + j) = dc * *cp.add(j) * f * (1.0 - f);
+                        *dg.add(2 * hidden + j) = dc * i * (1.0 - cc * cc);
+                        *dg.add(3 * hidden + j) = d * tc * o * (1.0 - o);
+                        *dcp.add(j) = dc * f;
+                    }
+                });
+                self.weight.grad.add(&self.last_xh[t].T().mul(&dz), true);
+                self.bias.grad.add(&sum_rows(&dz), true);
+
+                let dxh = dz.mul(&wt);
+                copy_cols(&dxh, 0, &ret, t * in_features, in_features);
+                if cut_gradient(t, self.bptt_steps) {
+                    dh_next = zeros(h, hidden);
+                    dc_next = zeros(h, hidden);
+                } else {
+                    dh_next = slice_cols(&dxh, in_features, hidden);
+                    dc_next = dc_prev;
+                }
+            }
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "Lstm",
+            &[
+                ("in_features", self.in_features),
+                ("hidden_size", self.hidden_size),
+                ("seq_len", self.seq_len),
+                ("return_sequences", self.return_sequences as usize),
+                ("stateful", self.stateful as usize),
+                ("bptt_steps", self.bptt_steps),
+            ],
+        )
+    }
+}
+
+pub struct Gru {
+    pub in_features: usize,
+    pub hidden_size: usize,
+    pub seq_len: usize,
+    pub return_sequences: bool,
+    pub stateful: bool,
+    pub bptt_steps: usize,
+
+    // (F+H)*3H, gates ordered as [reset, update, new]
+    // the first F rows act on x_t and the last H rows act on h_{t-1}
+    pub weight: Parameter,
+    pub bias: Parameter,
+
+    pub hidden: Matrix,
+
+    // [x_t, 0] and [0, h_{t-1}]
+    last_x0: Vec<Matrix>,
+    last_0h: Vec<Matrix>,
+    last_gates: Vec<Matrix>,
+    last_gh: Vec<Matrix>,
+    // h_0 .. h_T
+    last_h: Vec<Matrix>,
+}
+
+impl Gru {
+    pub unsafe fn new(
+        in_features: usize,
+        hidden_size: usize,
+        seq_len: usize,
+        return_sequences: bool,
+        stateful: bool,
+        bptt_steps: usize,
+    ) -> Self {
+        let weight = Matrix::new(in_features + hidden_size, 3 * hidden_size);
+        let bias = Matrix::new(1, 3 * hidden_size);
+        weight.normal_init();
+        bias.normal_init();
+        Self {
+            in_features,
+            hidden_size,
+            seq_len,
+            return_sequences,
+            stateful,
+            bptt_steps,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            hidden: Matrix::null(),
+            last_x0: Vec::new(),
+            last_0h: Vec::new(),
+            last_gates: Vec::new(),
+            last_gh: Vec::new(),
+            last_h: Vec::new(),
+        }
+    }
+    pub unsafe fn reset_state(&mut self) {
+        self.hidden = Matrix::null();
+    }
+}
+
+impl nn_trait::Layer for Gru {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            check_input(&input, self.seq_len, self.in_features);
+            let h = input.number_of_row();
+            let in_features = self.in_features;
+            let hidden = self.hidden_size;
+            self.last_x0.clear();
+            self.last_0h.clear();
+            self.last_gates.clear();
+            self.last_gh.clear();
+            self.last_h.clear();
+            self.last_h
+                .push(initial_state(&self.hidden, self.stateful, h, hidden));
+            let empty = zeros(h, hidden);
+            for t in 0..self.seq_len {
+                let h_prev = self.last_h.last().unwrap();
+                let x0 = concat_step(&input, t, in_features, &empty);
+                let oh = zeros(h, in_features + hidden);
+                copy_cols(h_prev, 0, &oh, in_features, hidden);
+
+                let gates = x0.mul(&self.weight.value);
+                gates.add_with_vector(&self.bias.value, true);
+                let gh = oh.mul(&self.weight.value);
+                let now = Matrix::new(h, hidden);
+                (0..h).into_par_iter().for_each(|idx| {
+                    let gx = gates.row_at(idx as isize);
+                    let gr = gh.row_at(idx as isize);
+                    let hp = h_prev.row_at(idx as isize);
+                    let hn = now.row_at(idx as isize);
+                    for j in 0..hidden {
+                        let r = sigmoid(*gx.add(j) + *gr.add(j));
+                        let z = sigmoid(*gx.add(hidden + j) + *gr.add(hidden + j));
+                        let n = (*gx.add(2 * hidden + j) + r * *gr.add(2 * hidden + j)).tanh();
+                        *gx.add(j) = r;
+                        *gx.add(hidden + j) = z;
+                        *gx.add(2 * hidden + j) = n;
+                        *hn.add(j) = (1.0 - z) * n + z * *hp.add(j);
+                    }
+                });
+                self.last_x0.push(x0);
+                self.last_0h.push(oh);
+                self.last_gates.push(gates);
+                self.last_gh.push(gh);
+                self.last_h.push(now);
+            }
+            if self.stateful {
+                self.hidden = self.last_h.last().unwrap().clone();
+            }
+            collect_output(&self.last_h[1..], self.return_sequences)
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let in_features = self.in_features;
+            let hidden = self.hidden_size;
+            let ret = Matrix::new(h, self.seq_len * in_features);
+            let wt = self.weight.value.T();
+
+            let mut dh_next = zeros(h, hidden);
+            for t in (0..self.seq_len).rev() {
+                let dh = dh_next;
+                if let Some(grad) =
+                    output_grad(&dLoss, t, self.seq_len, hidden, self.return_sequences)
+                {
+                    dh.add(&grad, true);
+                }
+                let gates = &self.last_gates[t];
+                let gh = &self.last_gh[t];
+                let h_prev = &self.last_h[t];
+                let dgx = Matrix::new(h, 3 * hidden);
+                let dgh = Matrix::new(h, 3 * hidden);
+                let dh_prev = Matrix::new(h, hidden);
+                (0..h).into_par_iter().for_each(|idx| {
+                    let g = gates.row_at(idx as isize);
+                    let gr = gh.row_at(idx as isize);
+                    let hp = h_prev.row_at(idx as isize);
+                    let dhn = dh.row_at(idx as isize);
+                    let dx = dgx.row_at(idx as isize);
+                    let dr = dgh.row_at(idx as isize);
+                    let dhp = dh_prev.row_at(idx as isize);
+                    for j in 0..hidden {
+                        let r = *g.add(j);
+                        let z = *g.add(hidden + j);
+                        let n = *g.add(2 * hidden + j);
+                        let d = *dhn.add(j);
+                        let dn = d * (1.0 - z) * (1.0 - n * n);
+                        let dz = d * (*hp.add(j) - n) * z * (1.0 - z);
+                        let drr = dn * *gr.add(2 * hidden + j) * r * (1.0 - r);
+                        *dx.add(j) = drr;
+                        *dx.add(hidden + j) = dz;
+                        *dx.add(2 * hidden + j) = dn;
+                        *dr.add(j) = drr;
+                        *dr.add(hidden + j) = dz;
+                        *dr.add(2 * hidden + j) = dn * r;
+                        *dhp.add(j) = d * z;
+                    }
+                });
+                self.weight.grad.add(&self.last_x0[t].T().mul(&dgx), true);
+                self.weight.grad.add(&self.last_0h[t].T().mul(&dgh), true);
+                self.bias.grad.add(&sum_rows(&dgx), true);
+
+                let dx0 = dgx.mul(&wt);
+                copy_cols(&dx0, 0, &ret, t * in_features, in_features);
+                dh_next = if cut_gradient(t, self.bptt_steps) {
+                    zeros(h, hidden)
+                } else {
+                    let doh = dgh.mul(&wt);
+                    dh_prev.add(&slice_cols(&doh, in_features, hidden), true);
+                    dh_prev
+                };
+            }
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "Gru",
+            &[
+                ("in_features", self.in_features),
+                ("hidden_size", self.hidden_size),
+                ("seq_len", self.seq_len),
+                ("return_sequences", self.return_sequences as usize),
+                ("stateful", self.stateful as usize),
+                ("bptt_steps", self.bptt_steps),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_layer, rand_mat};
+    use crate::utils::nn_trait::Layer;
+
+    // the three layers with random weights, the same for every call with the same seed
+    unsafe fn layers(
+        seq_len: usize,
+        return_sequences: bool,
+        stateful: bool,
+        bptt_steps: usize,
+    ) -> Vec<Box<dyn Layer>> {
+        let mut seed = 13u32;
+        let mut rnn = Rnn::new(3, 5, seq_len, return_sequences, stateful, bptt_steps);
+        rnn.weight.value = rand_mat(8, 5, &mut seed);
+        let mut lstm = Lstm::new(3, 5, seq_len, return_sequences, stateful, bptt_steps);
+        lstm.weight.value = rand_mat(8, 20, &mut seed);
+        let mut gru = Gru::new(3, 5, seq_len, return_sequences, stateful, bptt_steps);
+        gru.weight.value = rand_mat(8, 15, &mut seed);
+        vec![Box::new(rnn), Box::new(lstm), Box::new(gru)]
+    }
+
+    unsafe fn cols(m: &Matrix, start: usize, len: usize) -> Vec<f32> {
+        let (h, _) = m.shape();
+        (0..h)
+            .flat_map(|i| (start..start + len).map(move |j| m.at(i as isize, j as isize)))
+            .collect()
+    }
+
+    #[test]
+    fn full_bptt_matches_finite_differences() {
+        unsafe {
+            let mut seed = 3u32;
+            for return_sequences in [false, true] {
+                let x = rand_mat(3, 4 * 3, &mut seed);
+                for mut layer in layers(4, return_sequences, false, 0) {
+                    assert!(check_layer(layer.as_mut(), &x) < 2e-2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_bptt_stops_at_the_boundary() {
+        unsafe {
+            assert!(cut_gradient(4, 2) && cut_gradient(2, 2));
+            assert!(!cut_gradient(3, 2) && !cut_gradient(1, 2));
+            assert!(!cut_gradient(4, 0) && !cut_gradient(0, 0));
+
+            let mut seed = 3u32;
+            let x = rand_mat(3, 4 * 3, &mut seed);
+            let d_loss = rand_mat(3, 5, &mut seed);
+            let full = layers(4, false, false, 0);
+            let truncated = layers(4, false, false, 2);
+            for (mut full, mut truncated) in full.into_iter().zip(truncated) {
+                full.forward(x.clone());
+                let dx_full = full.backward(d_loss.clone());
+                truncated.forward(x.clone());
+                let dx = truncated.backward(d_loss.clone());
+                // steps 2 and 3 are inside the last chunk, 0 and 1 get nothing from it
+                assert_eq!(cols(&dx, 6, 6), cols(&dx_full, 6, 6));
+                assert!(cols(&dx, 0, 6).iter().all(|&v| v == 0.0));
+                assert!(cols(&dx_full, 0, 6).iter().any(|&v| v != 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn stateful_layers_carry_the_state_between_batches() {
+        unsafe {
+            let mut seed = 3u32;
+            let x = rand_mat(2, 6 * 3, &mut seed);
+            let first = Matrix::new(2, 3 * 3);
+            let second = Matrix::new(2, 3 * 3);
+            copy_cols(&x, 0, &first, 0, 9);
+            copy_cols(&x, 9, &second, 0, 9);
+            let whole = layers(6, true, false, 0);
+            let stateful = layers(3, true, true, 0);
+            for (mut whole, mut stateful) in whole.into_iter().zip(stateful) {
+                let expected = whole.forward(x.clone());
+                let y1 = stateful.forward(first.clone());
+                let y2 = stateful.forward(second.clone());
+                assert_eq!(cols(&y1, 0, 15), cols(&expected, 0, 15));
+                for (a, b) in cols(&y2, 0, 15).iter().zip(cols(&expected, 15, 15)) {
+                    assert!((a - b).abs() < 1e-6);
+                }
+            }
+            // without state, or after a reset, every batch starts from zeros
+            let mut rnn = Rnn::new(3, 5, 3, true, true, 0);
+            let y1 = rnn.forward(first.clone());
+            rnn.reset_state();
+            assert_eq!(cols(&rnn.forward(first.clone()), 0, 15), cols(&y1, 0, 15));
+            let mut rnn = Rnn::new(3, 5, 3, true, false, 0);
+            rnn.forward(first.clone());
+            assert_eq!(cols(&rnn.forward(first), 0, 15), cols(&y1, 0, 15));
+        }
+    }
+}
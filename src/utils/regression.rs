@@ -0,0 +1,170 @@
+use crate::utils::mat::Matrix;
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Prediction;
+use rayon::prelude::*;
+
+// per-sample loss is the mean of f over the columns, f maps pred - target to (loss, d loss)
+unsafe fn elementwise_loss<F>(input: &Matrix, target: &Matrix, grad: &mut Matrix, f: F) -> Matrix
+where
+    F: Fn(f32) -> (f32, f32) + Sync,
+{
+    let (h, w) = input.shape();
+    if target.shape() != (h, w) {
+        panic!("call regression head with unmatched target shape");
+    }
+    let ret = Matrix::new(h, 1);
+    *grad = Matrix::new(h, w);
+    (0..h).into_par_iter().for_each(|idx| {
+        let src_row = input.row_at(idx as isize);
+        let target_row = target.row_at(idx as isize);
+        let grad_row = grad.row_at(idx as isize);
+        let mut loss = 0f32;
+        for i in 0..w {
+            let (l, g) = f(*src_row.add(i) - *target_row.add(i));
+            loss += l;
+            *grad_row.add(i) = g / w as f32;
+        }
+        *ret.row_at(idx as isize) = loss / w as f32;
+    });
+    ret
+}
+
+pub struct MeanSquaredError {
+    pub grad: Matrix,
+}
+
+impl MeanSquaredError {
+    pub unsafe fn new() -> Self {
+        Self {
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for MeanSquaredError {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe { elementwise_loss(&input, &target, &mut self.grad, |d| (d * d, 2.0 * d)) }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        Prediction::Values(input.clone())
+    }
+    fn kind(&self) -> &'static str {
+        "MeanSquaredError"
+    }
+}
+
+pub struct MeanAbsoluteError {
+    pub grad: Matrix,
+}
+
+impl MeanAbsoluteError {
+    pub unsafe fn new() -> Self {
+        Self {
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for MeanAbsoluteError {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        unsafe {
+            elementwise_loss(&input, &target, &mut self.grad, |d| {
+                let g = if d > 0.0 {
+                    1.0
+                } else if d < 0.0 {
+                    -1.0
+                } else {
+                    0.0
+                };
+                (d.abs(), g)
+            })
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        Prediction::Values(input.clone())
+    }
+    fn kind(&self) -> &'static str {
+        "MeanAbsoluteError"
+    }
+}
+
+// quadratic below delta and linear above it
+// smooth L1 is the same curve divided by its beta
+pub struct HuberLoss {
+    pub delta: f32,
+    pub scale: f32,
+    pub grad: Matrix,
+}
+
+impl HuberLoss {
+    pub unsafe fn new(delta: f32) -> Self {
+        Self {
+            delta,
+            scale: 1.0,
+            grad: Matrix::null(),
+        }
+    }
+    pub unsafe fn smooth_l1(beta: f32) -> Self {
+        Self {
+            delta: beta,
+            scale: 1.0 / beta,
+            grad: Matrix::null(),
+        }
+    }
+}
+
+impl nn_trait::Head for HuberLoss {
+    fn forward(&mut self, input: Matrix, target: Matrix) -> Matrix {
+        let delta = self.delta;
+        let scale = self.scale;
+        unsafe {
+            elementwise_loss(&input, &target, &mut self.grad, |d| {
+                if d.abs() < delta {
+                    (0.5 * d * d * scale, d * scale)
+                } else {
+                    (
+                        delta * (d.abs() - 0.5 * delta) * scale,
+                        delta * d.signum() * scale,
+                    )
+                }
+            })
+        }
+    }
+    fn backward(&mut self, _: Matrix) -> Matrix {
+        self.grad.clone()
+    }
+    fn eval_forward(&self, input: &Matrix) -> Prediction {
+        Prediction::Values(input.clone())
+    }
+    fn kind(&self) -> &'static str {
+        "HuberLoss"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_head, rand_mat};
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 17u32;
+            let x = rand_mat(4, 5, &mut seed);
+            let target = rand_mat(4, 5, &mut seed);
+            assert!(check_head(&mut MeanSquaredError::new(), &x, &target) < 1e-2);
+            assert!(check_head(&mut HuberLoss::new(0.5), &x, &target) < 2e-2);
+            assert!(check_head(&mut HuberLoss::smooth_l1(0.5), &x, &target) < 2e-2);
+            // every residual away from the kink at 0
+            let target = x.add_with_numeric(0.3, false).unwrap();
+            *target.row_at(1).add(2) -= 0.6;
+            assert!(check_head(&mut MeanAbsoluteError::new(), &x, &target) < 1e-2);
+        }
+    }
+}
@@ -25,7 +25,7 @@ impl nn_trait::Layer for ReluLayer {
             let cmp = x86_64::_mm256_set1_ps(0.0);
             (0..h).into_par_iter().for_each(|idx| {
                 let src = input.row_at(idx as isize);
-                for i in (0..input.number_of_real_col()).step_by(256 / size_of::<f32>()) {
+                for i in (0..input.number_of_real_col()).step_by(32 / size_of::<f32>()) {
                     let val = x86_64::_mm256_max_ps(x86_64::_mm256_load_ps(src.add(i)), cmp);
                     x86_64::_mm256_store_ps(src.add(i), val);
                 }
@@ -40,7 +40,7 @@ impl nn_trait::Layer for ReluLayer {
             (0..h).into_par_iter().for_each(|idx| {
                 let src = self.last_input.row_at(idx as isize);
                 let dst = dLoss.row_at(idx as isize);
-                for i in (0..dLoss.number_of_real_col()).step_by(256 / size_of::<f32>()) {
+                for i in (0..dLoss.number_of_real_col()).step_by(32 / size_of::<f32>()) {
                     let val = x86_64::_mm256_load_ps(src.add(i));
                     let mask = std::arch::x86_64::_mm256_cmp_ps::<_CMP_LE_OQ>(val, cmp);
 
@@ -52,7 +52,41 @@ impl nn_trait::Layer for ReluLayer {
             dLoss
         }
     }
-    fn trainable(&self) -> bool {
-        false
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new("ReluLayer", &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::nn_trait::Layer;
+
+    #[test]
+    fn rectifies_every_column() {
+        unsafe {
+            // 13 columns, more than one register and not a multiple of it
+            let (h, w) = (3, 13);
+            let value = |i: usize, j: usize| (i * w + j) as f32 * 0.5 - 9.0;
+            let x = Matrix::new(h, w);
+            let d_loss = Matrix::new(h, w);
+            for i in 0..h {
+                for j in 0..w {
+                    *x.row_at(i as isize).add(j) = value(i, j);
+                    *d_loss.row_at(i as isize).add(j) = 1.0;
+                }
+            }
+            let mut relu = ReluLayer::new();
+            let y = relu.forward(x);
+            let dx = relu.backward(d_loss);
+            for i in 0..h {
+                for j in 0..w {
+                    let expected = value(i, j).max(0.0);
+                    assert_eq!(y.at(i as isize, j as isize), expected);
+                    let slope = if expected > 0.0 { 1.0 } else { 0.0 };
+                    assert_eq!(dx.at(i as isize, j as isize), slope);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,546 @@
+use crate::utils::checkpoint::{invalid, Reader, Stateful, Writer};
+use crate::utils::nn_trait::Optimizer;
+use std::f32::consts::PI;
+use std::io::Result;
+
+// call step before every iteration, or at the start of every epoch, depending on what the
+// schedule is counted in, the first call sets the rate of iteration / epoch 0
+// the base rate is the optimizer's rate at the first call
+// the state holds the position in the schedule, the settings come from the constructor
+pub trait Scheduler: Stateful {
+    fn step(&mut self, opt: &mut dyn Optimizer);
+}
+
+fn put_optional(w: &mut Writer, rate: Option<f32>) {
+    w.put_bool(rate.is_some());
+    w.put_f32(rate.unwrap_or(0.0));
+}
+
+fn get_optional(r: &mut Reader) -> Result<Option<f32>> {
+    let some = r.get_bool()?;
+    let rate = r.get_f32()?;
+    Ok(if some { Some(rate) } else { None })
+}
+
+// base * gamma^(count / step_size)
+pub struct StepDecay {
+    pub step_size: usize,
+    pub gamma: f32,
+    base_rate: Option<f32>,
+    count: usize,
+}
+
+impl StepDecay {
+    pub fn new(step_size: usize, gamma: f32) -> Self {
+        if step_size == 0 {
+            panic!("StepDecay needs a positive step size");
+        }
+        Self {
+            step_size,
+            gamma,
+            base_rate: None,
+            count: 0,
+        }
+    }
+}
+
+impl Stateful for StepDecay {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("StepDecay");
+        put_optional(w, self.base_rate);
+        w.put_u64(self.count as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("StepDecay")?;
+        self.base_rate = get_optional(r)?;
+        self.count = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+impl Scheduler for StepDecay {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let base = *self.base_rate.get_or_insert(opt.rate());
+        opt.set_rate(base * self.gamma.powi((self.count / self.step_size) as i32));
+        self.count += 1;
+    }
+}
+
+// base * gamma^(number of milestones reached)
+pub struct MultiStepDecay {
+    pub milestones: Vec<usize>,
+    pub gamma: f32,
+    base_rate: Option<f32>,
+    count: usize,
+}
+
+impl MultiStepDecay {
+    pub fn new(milestones: Vec<usize>, gamma: f32) -> Self {
+        Self {
+            milestones,
+            gamma,
+            base_rate: None,
+            count: 0,
+        }
+    }
+}
+
+impl Stateful for MultiStepDecay {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("MultiStepDecay");
+        put_optional(w, self.base_rate);
+        w.put_u64(self.count as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("MultiStepDecay")?;
+        self.base_rate = get_optional(r)?;
+        self.count = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+impl Scheduler for MultiStepDecay {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let base = *self.base_rate.get_or_insert(opt.rate());
+        let reached = self.milestones.iter().filter(|&&m| m <= self.count).count();
+        opt.set_rate(base * self.gamma.powi(reached as i32));
+        self.count += 1;
+    }
+}
+
+// base * gamma^count
+pub struct ExponentialDecay {
+    pub gamma: f32,
+    base_rate: Option<f32>,
+    count: usize,
+}
+
+impl ExponentialDecay {
+    pub fn new(gamma: f32) -> Self {
+        Self {
+            gamma,
+            base_rate: None,
+            count: 0,
+        }
+    }
+}
+
+impl Stateful for ExponentialDecay {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("ExponentialDecay");
+        put_optional(w, self.base_rate);
+        w.put_u64(self.count as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("ExponentialDecay")?;
+        self.base_rate = get_optional(r)?;
+        self.count = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+impl Scheduler for ExponentialDecay {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let base = *self.base_rate.get_or_insert(opt.rate());
+        opt.set_rate(base * self.gamma.powi(self.count as i32));
+        self.count += 1;
+    }
+}
+
+// half a cosine from base down to min_rate over the period, then back to base
+// the first period is first_period long, every next one period_mult times the last
+pub struct CosineWarmRestarts {
+    pub first_period: usize,
+    pub period_mult: usize,
+    pub min_rate: f32,
+    base_rate: Option<f32>,
+    period: usize,
+    // position in the current period
+    count: usize,
+}
+
+impl CosineWarmRestarts {
+    pub fn new(first_period: usize, period_mult: usize, min_rate: f32) -> Self {
+        if first_period == 0 || period_mult == 0 {
+            panic!("CosineWarmRestarts needs a positive period");
+        }
+        Self {
+            first_period,
+            period_mult,
+            min_rate,
+            base_rate: None,
+            period: first_period,
+            count: 0,
+        }
+    }
+}
+
+impl Stateful for CosineWarmRestarts {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("CosineWarmRestarts");
+        put_optional(w, self.base_rate);
+        w.put_u64(self.period as u64);
+        w.put_u64(self.count as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("CosineWarmRestarts")?;
+        self.base_rate = get_optional(r)?;
+        self.period = r.get_u64()? as usize;
+        self.count = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+impl Scheduler for CosineWarmRestarts {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let base = *self.base_rate.get_or_insert(opt.rate());
+        if self.count == self.period {
+            self.count = 0;
+            self.period *= self.period_mult;
+        }
+        let progress = self.count as f32 / self.period as f32;
+        opt.set_rate(self.min_rate + (base - self.min_rate) * 0.5 * (1.0 + (PI * progress).cos()));
+        self.count += 1;
+    }
+}
+
+// ramps linearly from start_factor * base to base over warmup_steps, then hands over to
+// the next schedule, which sees base as its own base rate, or stays at base without one
+pub struct LinearWarmup {
+    pub warmup_steps: usize,
+    pub start_factor: f32,
+    pub next: Option<Box<dyn Scheduler>>,
+    base_rate: Option<f32>,
+    count: usize,
+}
+
+impl LinearWarmup {
+    pub fn new(warmup_steps: usize, start_factor: f32, next: Option<Box<dyn Scheduler>>) -> Self {
+        Self {
+            warmup_steps,
+            start_factor,
+            next,
+            base_rate: None,
+            count: 0,
+        }
+    }
+}
+
+// followed by the state of the next schedule
+impl Stateful for LinearWarmup {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("LinearWarmup");
+        put_optional(w, self.base_rate);
+        w.put_u64(self.count as u64);
+        w.put_bool(self.next.is_some());
+        if let Some(next) = self.next.as_ref() {
+            next.write_state(w);
+        }
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("LinearWarmup")?;
+        self.base_rate = get_optional(r)?;
+        self.count = r.get_u64()? as usize;
+        if r.get_bool()? != self.next.is_some() {
+            return Err(invalid(
+                "LinearWarmup state does not match its next schedule".to_string(),
+            ));
+        }
+        if let Some(next) = self.next.as_mut() {
+            next.read_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Scheduler for LinearWarmup {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let base = *self.base_rate.get_or_insert(opt.rate());
+        if self.count < self.warmup_steps {
+            let progress = self.count as f32 / self.warmup_steps as f32;
+            opt.set_rate(base * (self.start_factor + (1.0 - self.start_factor) * progress));
+            self.count += 1;
+            return;
+        }
+        opt.set_rate(base);
+        if let Some(next) = self.next.as_mut() {
+            next.step(opt);
+        }
+    }
+}
+
+// one cycle over total_steps, counted in iterations
+// cosine from max_rate / div_factor up to max_rate over the first warmup_fraction of the
+// steps, then cosine down to max_rate / div_factor / final_div_factor
+// max_rate is given here rather than taken from the optimizer
+pub struct OneCycle {
+    pub max_rate: f32,
+    pub total_steps: usize,
+    pub warmup_fraction: f32,
+    pub div_factor: f32,
+    pub final_div_factor: f32,
+    count: usize,
+}
+
+impl OneCycle {
+    pub fn new(
+        max_rate: f32,
+        total_steps: usize,
+        warmup_fraction: f32,
+        div_factor: f32,
+        final_div_factor: f32,
+    ) -> Self {
+        Self {
+            max_rate,
+            total_steps,
+            warmup_fraction,
+            div_factor,
+            final_div_factor,
+            count: 0,
+        }
+    }
+}
+
+fn cosine_between(from: f32, to: f32, progress: f32) -> f32 {
+    to + (from - to) * 0.5 * (1.0 + (PI * progress.clamp(0.0, 1.0)).cos())
+}
+
+impl Stateful for OneCycle {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("OneCycle");
+        w.put_u64(self.count as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("OneCycle")?;
+        self.count = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+impl Scheduler for OneCycle {
+    fn step(&mut self, opt: &mut dyn Optimizer) {
+        let initial = self.max_rate / self.div_factor;
+        let last = initial / self.final_div_factor;
+        let warmup = (self.warmup_fraction * self.total_steps as f32) as usize;
+        let rate = if self.count < warmup {
+            cosine_between(initial, self.max_rate, self.count as f32 / warmup as f32)
+        } else {
+            let rest = self.total_steps.saturating_sub(warmup + 1).max(1);
+            cosine_between(
+                self.max_rate,
+                last,
+                (self.count - warmup) as f32 / rest as f32,
+            )
+        };
+        opt.set_rate(rate);
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PlateauMode {
+    // the metric is a loss
+    Min,
+    // the metric is an accuracy
+    Max,
+}
+
+// multiplies the rate by factor once the metric has not improved by more than the relative
+// threshold for patience epochs, then waits cooldown epochs before counting again
+// driven by a validation metric, so it has its own step
+pub struct ReduceOnPlateau {
+    pub mode: PlateauMode,
+    pub factor: f32,
+    pub patience: usize,
+    pub threshold: f32,
+    pub cooldown: usize,
+    pub min_rate: f32,
+    best: Option<f32>,
+    bad_epochs: usize,
+    cooldown_left: usize,
+}
+
+impl ReduceOnPlateau {
+    pub fn new(
+        mode: PlateauMode,
+        factor: f32,
+        patience: usize,
+        threshold: f32,
+        cooldown: usize,
+        min_rate: f32,
+    ) -> Self {
+        Self {
+            mode,
+            factor,
+            patience,
+            threshold,
+            cooldown,
+            min_rate,
+            best: None,
+            bad_epochs: 0,
+            cooldown_left: 0,
+        }
+    }
+
+    fn improved(&self, metric: f32) -> bool {
+        match (self.best, self.mode) {
+            (None, _) => true,
+            (Some(best), PlateauMode::Min) => metric < best - best.abs() * self.threshold,
+            (Some(best), PlateauMode::Max) => metric > best + best.abs() * self.threshold,
+        }
+    }
+
+    pub fn step(&mut self, opt: &mut dyn Optimizer, metric: f32) {
+        if self.improved(metric) {
+            self.best = Some(metric);
+            self.bad_epochs = 0;
+        } else {
+            self.bad_epochs += 1;
+        }
+        if self.cooldown_left > 0 {
+            self.cooldown_left -= 1;
+            self.bad_epochs = 0;
+        }
+        if self.bad_epochs > self.patience {
+            opt.set_rate((opt.rate() * self.factor).max(self.min_rate));
+            self.cooldown_left = self.cooldown;
+            self.bad_epochs = 0;
+        }
+    }
+}
+
+impl Stateful for ReduceOnPlateau {
+    fn write_state(&self, w: &mut Writer) {
+        w.put_str("ReduceOnPlateau");
+        put_optional(w, self.best);
+        w.put_u64(self.bad_epochs as u64);
+        w.put_u64(self.cooldown_left as u64);
+    }
+    fn read_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.expect_str("ReduceOnPlateau")?;
+        self.best = get_optional(r)?;
+        self.bad_epochs = r.get_u64()? as usize;
+        self.cooldown_left = r.get_u64()? as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::optimizer::SGD;
+
+    // the rate set by every one of n steps, from a base rate of 0.1
+    fn rates(scheduler: &mut dyn Scheduler, n: usize) -> Vec<f32> {
+        let mut opt = SGD::new(0.1, 0.0, 0.0);
+        (0..n)
+            .map(|_| {
+                scheduler.step(&mut opt);
+                opt.rate()
+            })
+            .collect()
+    }
+
+    fn assert_rates(got: &[f32], expected: &[f32]) {
+        assert_eq!(got.len(), expected.len());
+        for (t, (g, e)) in got.iter().zip(expected).enumerate() {
+            assert!((g - e).abs() < 1e-6, "{} != {} at step {}", g, e, t);
+        }
+    }
+
+    #[test]
+    fn decays_follow_their_closed_form() {
+        assert_rates(
+            &rates(&mut StepDecay::new(3, 0.5), 7),
+            &[0.1, 0.1, 0.1, 0.05, 0.05, 0.05, 0.025],
+        );
+        assert_rates(
+            &rates(&mut MultiStepDecay::new(vec![2, 5], 0.1), 6),
+            &[0.1, 0.1, 0.01, 0.01, 0.01, 0.001],
+        );
+        let expected: Vec<f32> = (0..5).map(|t| 0.1 * 0.9f32.powi(t)).collect();
+        assert_rates(&rates(&mut ExponentialDecay::new(0.9), 5), &expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "positive step size")]
+    fn zero_step_size_is_refused() {
+        StepDecay::new(0, 0.5);
+    }
+
+    #[test]
+    fn cosine_restarts_at_the_end_of_every_period() {
+        // periods of 2, 4 and 8 steps, so the rate is back at the base at steps 2 and 6
+        let cosine = |position: f32, period: f32| {
+            0.01 + (0.1 - 0.01) * 0.5 * (1.0 + (PI * position / period).cos())
+        };
+        let expected = [
+            cosine(0.0, 2.0),
+            cosine(1.0, 2.0),
+            cosine(0.0, 4.0),
+            cosine(1.0, 4.0),
+            cosine(2.0, 4.0),
+            cosine(3.0, 4.0),
+            cosine(0.0, 8.0),
+            cosine(1.0, 8.0),
+        ];
+        let got = rates(&mut CosineWarmRestarts::new(2, 2, 0.01), 8);
+        assert_rates(&got, &expected);
+        assert!((got[2] - 0.1).abs() < 1e-6 && (got[6] - 0.1).abs() < 1e-6);
+        assert!((got[4] - 0.055).abs() < 1e-6);
+    }
+
+    #[test]
+    fn warmup_hands_over_to_the_next_schedule() {
+        let next: Box<dyn Scheduler> = Box::new(ExponentialDecay::new(0.5));
+        assert_rates(
+            &rates(&mut LinearWarmup::new(4, 0.25, Some(next)), 7),
+            &[0.025, 0.04375, 0.0625, 0.08125, 0.1, 0.05, 0.025],
+        );
+        assert_rates(
+            &rates(&mut LinearWarmup::new(2, 0.5, None), 4),
+            &[0.05, 0.075, 0.1, 0.1],
+        );
+    }
+
+    #[test]
+    fn one_cycle_goes_up_then_down() {
+        // initial 1 / 10, last 1 / 10 / 100, three warmup steps and six down
+        let got = rates(&mut OneCycle::new(1.0, 10, 0.3, 10.0, 100.0), 10);
+        let up = |progress: f32| 1.0 - 0.9 * 0.5 * (1.0 + (PI * progress).cos());
+        let down = |progress: f32| 0.001 + 0.999 * 0.5 * (1.0 + (PI * progress).cos());
+        let mut expected: Vec<f32> = (0..3).map(|t| up(t as f32 / 3.0)).collect();
+        expected.extend((0..7).map(|t| down(t as f32 / 6.0)));
+        assert_rates(&got, &expected);
+        assert_rates(&got[..4], &[0.1, 0.325, 0.775, 1.0]);
+        assert!((got[9] - 0.001).abs() < 1e-6);
+    }
+
+    #[test]
+    fn plateau_waits_for_patience_and_cooldown() {
+        let mut opt = SGD::new(0.1, 0.0, 0.0);
+        let mut plateau = ReduceOnPlateau::new(PlateauMode::Min, 0.5, 2, 0.0, 1, 0.0);
+        let got: Vec<f32> = (0..8)
+            .map(|_| {
+                plateau.step(&mut opt, 1.0);
+                opt.rate()
+            })
+            .collect();
+        // three bad epochs, then one of cooldown before three more
+        assert_rates(&got, &[0.1, 0.1, 0.1, 0.05, 0.05, 0.05, 0.05, 0.025]);
+
+        // a gain below the relative threshold is not an improvement, one above it is
+        let mut opt = SGD::new(0.1, 0.0, 0.0);
+        let mut plateau = ReduceOnPlateau::new(PlateauMode::Max, 0.5, 1, 0.1, 0, 0.08);
+        for (metric, rate) in [
+            (1.0, 0.1),
+            (1.05, 0.1),
+            (1.2, 0.1),
+            (1.25, 0.1),
+            (1.3, 0.08),
+        ] {
+            plateau.step(&mut opt, metric);
+            assert!((opt.rate() - rate).abs() < 1e-6);
+        }
+    }
+}
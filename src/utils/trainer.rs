@@ -0,0 +1,513 @@
+use crate::utils::checkpoint::{invalid, Stateful};
+use crate::utils::dataloader::DataLoader;
+use crate::utils::mat::Matrix;
+use crate::utils::network::Network;
+use crate::utils::nn_trait::{DataSet, Prediction};
+use crate::utils::scheduler::PlateauMode;
+use std::io::{Error, ErrorKind, Result};
+
+// after every update
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BatchStats {
+    pub epoch: usize,
+    // batches of this epoch so far, from 1
+    pub batch: usize,
+    // updates since the start of the run
+    pub iteration: usize,
+    // samples of this epoch trained so far, the loader_position of a checkpoint saved here
+    pub position: usize,
+    // mean over the samples of the batch
+    pub loss: f32,
+    pub grad_norm: f32,
+}
+
+// after the validation pass of every epoch
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EpochStats {
+    pub epoch: usize,
+    pub iteration: usize,
+    pub train_loss: f32,
+    pub valid_loss: f32,
+    // only for heads that predict classes
+    pub valid_accuracy: Option<f32>,
+    // the monitored metric is the best so far, the weights were kept
+    pub best: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Monitor {
+    TrainLoss,
+    ValidLoss,
+    ValidAccuracy,
+}
+
+impl Monitor {
+    fn mode(&self) -> PlateauMode {
+        match self {
+            Monitor::ValidAccuracy => PlateauMode::Max,
+            _ => PlateauMode::Min,
+        }
+    }
+    fn of(&self, stats: &EpochStats) -> Result<f32> {
+        match self {
+            Monitor::TrainLoss => Ok(stats.train_loss),
+            Monitor::ValidLoss => Ok(stats.valid_loss),
+            Monitor::ValidAccuracy => stats.valid_accuracy.ok_or_else(no_accuracy),
+        }
+    }
+}
+
+fn no_accuracy() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "ValidAccuracy needs a head that predicts classes",
+    )
+}
+
+// the shuffling seed of the training set in the given epoch
+pub fn loader_seed(seed: u32, epoch: usize) -> u32 {
+    seed ^ ((epoch as u32) << 10)
+}
+
+// stops once the monitored metric has not improved by more than min_delta for patience epochs
+// the best weights are only replaced by such an improvement as well
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EarlyStopping {
+    pub patience: usize,
+    pub min_delta: f32,
+}
+
+type BatchCallback = Box<dyn FnMut(&mut Network, &BatchStats)>;
+type EpochCallback = Box<dyn FnMut(&mut Network, &EpochStats)>;
+
+// the epoch loop, the train and validation datasets are fetched in batches of batch_size,
+// the training order of every epoch comes from loader_seed
+pub struct Trainer<D>
+where
+    D: DataSet + std::marker::Sync,
+{
+    pub network: Network,
+    pub train: D,
+    pub valid: D,
+    pub batch_size: usize,
+    pub seed: u32,
+    pub monitor: Monitor,
+    pub early_stopping: Option<EarlyStopping>,
+    // the best weights are also saved here, in Network::save format
+    pub best_path: Option<String>,
+    batch_callbacks: Vec<BatchCallback>,
+    epoch_callbacks: Vec<EpochCallback>,
+    best: Option<(f32, Vec<Matrix>)>,
+    bad_epochs: usize,
+    epoch: usize,
+    iteration: usize,
+    // samples of the current epoch already trained, set by resume
+    position: usize,
+}
+
+impl<D> Trainer<D>
+where
+    D: DataSet + std::marker::Sync,
+{
+    pub fn new(network: Network, train: D, valid: D, batch_size: usize) -> Self {
+        Self {
+            network,
+            train,
+            valid,
+            batch_size,
+            seed: 0,
+            monitor: Monitor::ValidLoss,
+            early_stopping: None,
+            best_path: None,
+            batch_callbacks: Vec::new(),
+            epoch_callbacks: Vec::new(),
+            best: None,
+            bad_epochs: 0,
+            epoch: 0,
+            iteration: 0,
+            position: 0,
+        }
+    }
+
+    // callbacks run in the order they were added, with the network to log, schedule or save
+    pub fn on_batch_end(&mut self, f: impl FnMut(&mut Network, &BatchStats) + 'static) {
+        self.batch_callbacks.push(Box::new(f));
+    }
+    pub fn on_epoch_end(&mut self, f: impl FnMut(&mut Network, &EpochStats) + 'static) {
+        self.epoch_callbacks.push(Box::new(f));
+    }
+
+    // epochs finished so far, fit continues from here
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+    pub fn iteration(&self) -> usize {
+        self.iteration
+    }
+    // the best value of the monitored metric so far
+    pub fn best(&self) -> Option<f32> {
+        self.best.as_ref().map(|(metric, _)| *metric)
+    }
+
+    // continues a run from a Network::save_training checkpoint, restoring the weights, the
+    // optimizer, the schedulers, the counters and how far the loader got into the epoch
+    // the best weights and the early stopping count start over
+    pub unsafe fn resume(
+        &mut self,
+        path: &str,
+        schedulers: &mut [&mut dyn Stateful],
+    ) -> Result<()> {
+        let progress = self.network.load_training(path, schedulers)?;
+        if progress.loader_seed != loader_seed(self.seed, progress.epoch) {
+            return Err(invalid(format!(
+                "{} was written by a run with another seed",
+                path
+            )));
+        }
+        self.epoch = progress.epoch;
+        self.iteration = progress.iteration;
+        self.position = progress.loader_position;
+        Ok(())
+    }
+
+    // the mean loss over the samples trained, the rest of the epoch after a resume
+    pub unsafe fn train_epoch(&mut self) -> f32 {
+        let seed = loader_seed(self.seed, self.epoch);
+        let start = std::mem::take(&mut self.position).min(self.train.len());
+        let dataloader = DataLoader::resume(&self.train, self.batch_size, seed, start);
+        let skipped = start.div_ceil(self.batch_size);
+        let mut total = 0f32;
+        let mut position = start;
+        for (batch, (image, gt)) in dataloader.enumerate() {
+            self.iteration += 1;
+            let pred = self.network.forward(image);
+            let loss = self.network.calc_loss(pred, gt);
+            let (h, _) = loss.shape();
+            let sum = (0..h).map(|idx| loss.at(idx as isize, 0)).sum::<f32>();
+            total += sum;
+            position += h;
+            self.network.zero_grad();
+            self.network.backward(loss);
+            let grad_norm = self.network.update_parameters();
+            let stats = BatchStats {
+                epoch: self.epoch,
+                batch: skipped + batch + 1,
+                iteration: self.iteration,
+                position,
+                loss: sum / h as f32,
+                grad_norm,
+            };
+            for f in self.batch_callbacks.iter_mut() {
+                f(&mut self.network, &stats);
+            }
+        }
+        // nothing is left to train when the checkpoint was saved after the last batch
+        total / (position - start).max(1) as f32
+    }
+
+    // mean loss and, for heads that predict classes, the accuracy on the validation set
+    pub unsafe fn evaluate(&mut self) -> (f32, Option<f32>) {
+        let dataloader = DataLoader::new(&self.valid, self.batch_size, 0);
+        let mut total = 0f32;
+        let mut ok = Some(0usize);
+        for (image, gt) in dataloader {
+            let pred = self.network.forward(image);
+            let classes = match self.network.get_result(&pred) {
+                x @ (Prediction::Classes(_) | Prediction::Distribution { .. }) => Some(x.classes()),
+                _ => None,
+            };
+            ok = ok.zip(classes).map(|(ok, classes)| {
+                ok + classes
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, &class)| {
+                        (gt.at(*idx as isize, class as isize) - 1.0).abs() < 1e-5
+                    })
+                    .count()
+            });
+            let loss = self.network.calc_loss(pred, gt);
+            let (h, _) = loss.shape();
+            total += (0..h).map(|idx| loss.at(idx as isize, 0)).sum::<f32>();
+        }
+        let len = self.valid.len() as f32;
+        (total / len, ok.map(|ok| ok as f32 / len))
+    }
+
+    fn improved(&self, metric: f32, min_delta: f32) -> bool {
+        match (&self.best, self.monitor.mode()) {
+            (None, _) => true,
+            (Some((best, _)), PlateauMode::Min) => metric < best - min_delta,
+            (Some((best, _)), PlateauMode::Max) => metric > best + min_delta,
+        }
+    }
+
+    // whether the head predicts classes, from one validation sample
+    unsafe fn predicts_classes(&mut self) -> bool {
+        let Some((image, _)) = DataLoader::new(&self.valid, 1, 0).next() else {
+            return false;
+        };
+        let pred = self.network.forward(image);
+        matches!(
+            self.network.get_result(&pred),
+            Prediction::Classes(_) | Prediction::Distribution { .. }
+        )
+    }
+
+    // runs up to epochs more epochs, returns the stats of every epoch that ran
+    // stops early once early_stopping runs out of patience
+    pub unsafe fn fit(&mut self, epochs: usize) -> Result<Vec<EpochStats>> {
+        if self.monitor == Monitor::ValidAccuracy && !self.predicts_classes() {
+            return Err(no_accuracy());
+        }
+        let mut history = Vec::new();
+        for _ in 0..epochs {
+            let train_loss = self.train_epoch();
+            let (valid_loss, valid_accuracy) = self.evaluate();
+            let mut stats = EpochStats {
+                epoch: self.epoch,
+                iteration: self.iteration,
+                train_loss,
+                valid_loss,
+                valid_accuracy,
+                best: false,
+            };
+            self.epoch += 1;
+
+            let metric = self.monitor.of(&stats)?;
+            let min_delta = self.early_stopping.map_or(0.0, |x| x.min_delta);
+            if self.improved(metric, min_delta) {
+                self.bad_epochs = 0;
+                let weights = self
+                    .network
+                    .parameters()
+                    .iter()
+                    .map(|p| p.value.clone())
+                    .collect();
+                self.best = Some((metric, weights));
+                if let Some(path) = &self.best_path {
+                    self.network.save(path)?;
+                }
+                stats.best = true;
+            } else {
+                self.bad_epochs += 1;
+            }
+
+            for f in self.epoch_callbacks.iter_mut() {
+                f(&mut self.network, &stats);
+            }
+            history.push(stats);
+            if let Some(early_stopping) = self.early_stopping {
+                if self.bad_epochs >= early_stopping.patience {
+                    break;
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    // puts the best weights seen back into the network, false if no epoch finished yet
+    pub unsafe fn restore_best(&mut self) -> bool {
+        match &self.best {
+            Some((_, weights)) => {
+                self.network.set_weights(weights.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::checkpoint::Progress;
+    use crate::utils::gradcheck::rand_mat;
+    use crate::utils::head::SoftMaxCrossEntropy;
+    use crate::utils::linear::LinearLayer;
+    use crate::utils::nn_trait::{Head, Layer};
+    use crate::utils::optimizer::SGD;
+    use crate::utils::regression::MeanSquaredError;
+    use crate::utils::scheduler::{Scheduler, StepDecay};
+
+    // noise with a bump at the column of the label, i % 10
+    struct ToySet {
+        images: Vec<Vec<f32>>,
+    }
+
+    impl DataSet for ToySet {
+        fn dim(&self) -> usize {
+            16
+        }
+        fn len(&self) -> usize {
+            self.images.len()
+        }
+        fn is_empty(&self) -> bool {
+            self.images.is_empty()
+        }
+        unsafe fn fetch_item(&self, idx: isize) -> (&[f32], u8) {
+            (&self.images[idx as usize], (idx % 10) as u8)
+        }
+    }
+
+    unsafe fn toy_set(len: usize, mut seed: u32) -> ToySet {
+        let x = rand_mat(len, 16, &mut seed);
+        let images = (0..len)
+            .map(|i| {
+                let mut image: Vec<f32> = (0..16).map(|j| x.at(i as isize, j)).collect();
+                image[i % 10] += 2.0;
+                image
+            })
+            .collect();
+        ToySet { images }
+    }
+
+    unsafe fn trainer(rate: f32, head: Box<dyn Head>) -> Trainer<ToySet> {
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(LinearLayer::new(16, 10))];
+        let network = Network::new(layers, head, Box::new(SGD::new(rate, 0.9, 0.0)));
+        let mut ret = Trainer::new(network, toy_set(44, 9), toy_set(20, 31), 8);
+        ret.seed = 5;
+        ret
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("tinynet_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    unsafe fn weight_bits(network: &mut Network) -> Vec<u32> {
+        network
+            .parameters()
+            .iter()
+            .flat_map(|p| {
+                let (h, w) = p.value.shape();
+                (0..h * w).map(move |i| p.value.at((i / w) as isize, (i % w) as isize).to_bits())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fit_learns_the_toy_set() {
+        unsafe {
+            let mut trainer = trainer(0.05, Box::new(SoftMaxCrossEntropy::new()));
+            trainer.monitor = Monitor::ValidAccuracy;
+            let history = trainer.fit(6).unwrap();
+            assert_eq!(history.len(), 6);
+            assert_eq!(trainer.epoch(), 6);
+            // six batches of 8, the last one of 4
+            assert_eq!(trainer.iteration(), 36);
+            assert!(history[5].train_loss < history[0].train_loss);
+            assert!(history[5].valid_accuracy.unwrap() > 0.5);
+            assert!(history[0].best);
+            let best = history
+                .iter()
+                .map(|stats| stats.valid_accuracy.unwrap())
+                .fold(0.0, f32::max);
+            assert_eq!(trainer.best(), Some(best));
+        }
+    }
+
+    #[test]
+    fn early_stopping_and_restore_best() {
+        unsafe {
+            let mut trainer = trainer(0.05, Box::new(SoftMaxCrossEntropy::new()));
+            trainer.early_stopping = Some(EarlyStopping {
+                patience: 2,
+                min_delta: 0.0,
+            });
+            // the weights are zeroed at the end of epoch 2, the velocity left in sgd
+            // moves them a little more but never back to where they were
+            trainer.on_epoch_end(|network, stats| {
+                if stats.epoch == 2 {
+                    for parameter in network.parameters() {
+                        parameter.value.fill_(0.0);
+                    }
+                    network.opt.set_rate(0.0);
+                }
+            });
+            let history = trainer.fit(10).unwrap();
+            assert_eq!(history.len(), 5);
+            assert!(history[3].valid_loss > history[2].valid_loss);
+            assert!(history[4].valid_loss > history[2].valid_loss);
+            assert!(!history[3].best && !history[4].best);
+
+            let best = trainer.best().unwrap();
+            assert!(trainer.restore_best());
+            let (valid_loss, _) = trainer.evaluate();
+            assert!((valid_loss - best).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn accuracy_of_a_regression_head_is_refused() {
+        unsafe {
+            let mut trainer = trainer(0.05, Box::new(MeanSquaredError::new()));
+            trainer.monitor = Monitor::ValidAccuracy;
+            let err = trainer.fit(1).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(trainer.epoch(), 0);
+        }
+    }
+
+    // two epochs with a step decay stepped after every batch, saving at iteration save_at
+    unsafe fn scheduled(path: &str, save_at: Option<usize>) -> Trainer<ToySet> {
+        let mut trainer = trainer(0.05, Box::new(SoftMaxCrossEntropy::new()));
+        let seed = trainer.seed;
+        let path = path.to_string();
+        let mut scheduler = StepDecay::new(4, 0.5);
+        trainer.on_batch_end(move |network, stats| {
+            scheduler.step(network.opt.as_mut());
+            if Some(stats.iteration) == save_at {
+                let progress = Progress {
+                    epoch: stats.epoch,
+                    iteration: stats.iteration,
+                    loader_seed: loader_seed(seed, stats.epoch),
+                    loader_position: stats.position,
+                };
+                network
+                    .save_training(&path, &progress, &[&scheduler])
+                    .unwrap();
+            }
+        });
+        trainer
+    }
+
+    #[test]
+    fn resumed_fit_ends_where_the_full_one_does() {
+        unsafe {
+            let path = temp_path("trainer_resume.tnts");
+            let mut full = scheduled(&path, None);
+            full.fit(2).unwrap();
+
+            // saves in the middle of the second epoch, after two of its six batches
+            scheduled(&path, Some(8)).fit(2).unwrap();
+
+            let mut resumed = trainer(0.05, Box::new(SoftMaxCrossEntropy::new()));
+            let mut scheduler = StepDecay::new(4, 0.5);
+            resumed.resume(&path, &mut [&mut scheduler]).unwrap();
+            assert_eq!((resumed.epoch(), resumed.iteration()), (1, 8));
+            let batches = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            let seen = batches.clone();
+            resumed.on_batch_end(move |network, stats| {
+                scheduler.step(network.opt.as_mut());
+                seen.borrow_mut().push((stats.batch, stats.position));
+            });
+            let history = resumed.fit(1).unwrap();
+            assert_eq!(history[0].epoch, 1);
+            assert_eq!(resumed.iteration(), 12);
+            assert_eq!(batches.borrow()[0], (3, 24));
+            assert_eq!(
+                weight_bits(&mut full.network),
+                weight_bits(&mut resumed.network)
+            );
+
+            // the order of the rest of the epoch depends on the seed
+            let mut other = trainer(0.05, Box::new(SoftMaxCrossEntropy::new()));
+            other.seed = 6;
+            let mut scheduler = StepDecay::new(4, 0.5);
+            assert!(other.resume(&path, &mut [&mut scheduler]).is_err());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::utils::attention::MultiHeadAttention;
+use crate::utils::layernorm::LayerNorm;
+use crate::utils::linear::LinearLayer;
+use crate::utils::mat::Matrix;
+use crate::utils::misc::{merge_rows, split_rows, sum_rows};
+use crate::utils::nn_trait;
+use crate::utils::nn_trait::Layer;
+use crate::utils::parameter::{prefix_parameters, Parameter};
+use crate::utils::relu::ReluLayer;
+use rayon::prelude::*;
+
+// post-norm encoder block on B*(TD)
+// x = norm1(x + attention(x)), x = norm2(x + ff2(relu(ff1(x))))
+pub struct TransformerEncoderLayer {
+    pub d_model: usize,
+    pub seq_len: usize,
+
+    pub attention: MultiHeadAttention,
+    pub norm1: LayerNorm,
+    pub ff1: LinearLayer,
+    pub relu: ReluLayer,
+    pub ff2: LinearLayer,
+    pub norm2: LayerNorm,
+}
+
+impl TransformerEncoderLayer {
+    pub unsafe fn new(
+        d_model: usize,
+        num_heads: usize,
+        dim_feedforward: usize,
+        seq_len: usize,
+        causal: bool,
+    ) -> Self {
+        let mut ret = Self {
+            d_model,
+            seq_len,
+            attention: MultiHeadAttention::new(d_model, num_heads, seq_len, causal),
+            norm1: LayerNorm::new(d_model),
+            ff1: LinearLayer::new(d_model, dim_feedforward),
+            relu: ReluLayer::new(),
+            ff2: LinearLayer::new(dim_feedforward, d_model),
+            norm2: LayerNorm::new(d_model),
+        };
+        prefix_parameters(ret.attention.parameters(), "attention");
+        prefix_parameters(ret.norm1.parameters(), "norm1");
+        prefix_parameters(ret.ff1.parameters(), "ff1");
+        prefix_parameters(ret.ff2.parameters(), "ff2");
+        prefix_parameters(ret.norm2.parameters(), "norm2");
+        ret
+    }
+}
+
+impl nn_trait::Layer for TransformerEncoderLayer {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            let now = self.attention.forward(input.clone());
+            now.add(&input, true);
+            let now = self.norm1.forward(now);
+
+            let tokens = split_rows(&now, self.d_model);
+            let res = self.ff1.forward(tokens);
+            let res = self.relu.forward(res);
+            let res = merge_rows(&self.ff2.forward(res), self.seq_len);
+            res.add(&now, true);
+            self.norm2.forward(res)
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let now = self.norm2.backward(dLoss);
+            let res = self.ff2.backward(split_rows(&now, self.d_model));
+            let res = self.relu.backward(res);
+            let res = merge_rows(&self.ff1.backward(res), self.seq_len);
+            res.add(&now, true);
+
+            let now = self.norm1.backward(res);
+            let ret = self.attention.backward(now.clone());
+            ret.add(&now, true);
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut ret = self.attention.parameters();
+        ret.extend(self.norm1.parameters());
+        ret.extend(self.ff1.parameters());
+        ret.extend(self.ff2.parameters());
+        ret.extend(self.norm2.parameters());
+        ret
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "TransformerEncoderLayer",
+            &[
+                ("d_model", self.d_model),
+                ("num_heads", self.attention.num_heads),
+                ("dim_feedforward", self.ff1.weight.value.number_of_col()),
+                ("seq_len", self.seq_len),
+                ("causal", self.attention.causal as usize),
+            ],
+        )
+    }
+}
+
+// B*HWC => B*(ND), every non-overlapping patch x patch block becomes one token
+// the learned position embedding doubles as the bias of the projection
+pub struct PatchEmbedding {
+    pub in_channels: usize,
+    pub im_row: usize,
+    pub im_col: usize,
+    pub patch_size: usize,
+    pub d_model: usize,
+    pub num_patches: usize,
+
+    // (patch * patch * C) * D
+    pub weight: Parameter,
+    // position embedding, 1 * (ND)
+    pub bias: Parameter,
+
+    last_patches: Matrix,
+}
+
+impl PatchEmbedding {
+    pub unsafe fn new(
+        in_channels: usize,
+        im_row: usize,
+        im_col: usize,
+        patch_size: usize,
+        d_model: usize,
+    ) -> Self {
+        if !im_row.is_multiple_of(patch_size) || !im_col.is_multiple_of(patch_size) {
+            panic!("image size must be divisible by patch_size");
+        }
+        let num_patches = (im_row / patch_size) * (im_col / patch_size);
+        let patch_dim = patch_size * patch_size * in_channels;
+        let weight = Matrix::new(patch_dim, d_model);
+        let bias = Matrix::new(1, num_patches * d_model);
+        weight.normal_init();
+        bias.normal_init();
+        Self {
+            in_channels,
+            im_row,
+            im_col,
+            patch_size,
+            d_model,
+            num_patches,
+            weight: Parameter::new("weight", weight, true),
+            bias: Parameter::new("bias", bias, false),
+            last_patches: Matrix::null(),
+        }
+    }
+
+    // offset of the first pixel of patch `n`, and of row `dy` inside it
+    fn pixel_offset(&self, n: usize, dy: usize) -> usize {
+        let patch_col = self.im_col / self.patch_size;
+        let row = n / patch_col * self.patch_size + dy;
+        let col = n % patch_col * self.patch_size;
+        (row * self.im_col + col) * self.in_channels
+    }
+}
+
+impl nn_trait::Layer for PatchEmbedding {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            if w != self.im_row * self.im_col * self.in_channels {
+                panic!("call PatchEmbedding with unmatched input shape");
+            }
+            let n = self.num_patches;
+            let line = self.patch_size * self.in_channels;
+            self.last_patches = Matrix::new(h * n, self.patch_size * line);
+            (0..h * n).into_par_iter().for_each(|idx| {
+                let src = input.row_at((idx / n) as isize);
+                let dst = self.last_patches.row_at(idx as isize);
+                for dy in 0..self.patch_size {
+                    let from = src.add(self.pixel_offset(idx % n, dy));
+                    std::ptr::copy_nonoverlapping(from, dst.add(dy * line), line);
+                }
+            });
+            let ret = merge_rows(&self.last_patches.mul(&self.weight.value), n);
+            ret.add_with_vector(&self.bias.value, true);
+            ret
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let n = self.num_patches;
+            let line = self.patch_size * self.in_channels;
+            self.bias.grad.add(&sum_rows(&dLoss), true);
+            let tokens = split_rows(&dLoss, self.d_model);
+            self.weight
+                .grad
+                .add(&self.last_patches.T().mul(&tokens), true);
+            let d_patches = tokens.mul(&self.weight.value.T());
+            let ret = Matrix::new(h, self.im_row * self.im_col * self.in_channels);
+            (0..h).into_par_iter().for_each(|b| {
+                let dst = ret.row_at(b as isize);
+                for p in 0..n {
+                    let src = d_patches.row_at((b * n + p) as isize);
+                    for dy in 0..self.patch_size {
+                        let to = dst.add(self.pixel_offset(p, dy));
+                        std::ptr::copy_nonoverlapping(src.add(dy * line), to, line);
+                    }
+                }
+            });
+            ret
+        }
+    }
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight, &mut self.bias]
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "PatchEmbedding",
+            &[
+                ("in_channels", self.in_channels),
+                ("im_row", self.im_row),
+                ("im_col", self.im_col),
+                ("patch_size", self.patch_size),
+                ("d_model", self.d_model),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_layer, rand_mat};
+
+    #[test]
+    fn encoder_backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let mut encoder = TransformerEncoderLayer::new(4, 2, 6, 3, false);
+            for linear in [
+                &mut encoder.attention.query,
+                &mut encoder.attention.key,
+                &mut encoder.attention.value,
+                &mut encoder.attention.output,
+            ] {
+                linear.weight.value = rand_mat(4, 4, &mut seed);
+            }
+            encoder.ff1.weight.value = rand_mat(4, 6, &mut seed);
+            encoder.ff2.weight.value = rand_mat(6, 4, &mut seed);
+            // keeps every relu away from its kink, where finite differences are meaningless
+            encoder.ff1.bias.value.fill_(4.0);
+            encoder.norm1.weight.value = rand_mat(1, 4, &mut seed);
+            let x = rand_mat(3, 12, &mut seed);
+            assert!(check_layer(&mut encoder, &x) < 2e-2);
+        }
+    }
+
+    #[test]
+    fn patch_embedding_backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 5u32;
+            let mut embedding = PatchEmbedding::new(2, 4, 6, 2, 3);
+            embedding.weight.value = rand_mat(8, 3, &mut seed);
+            let x = rand_mat(2, 48, &mut seed);
+            assert!(check_layer(&mut embedding, &x) < 2e-2);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::utils::mat::Matrix;
+use crate::utils::misc::zeros;
+use crate::utils::nn_trait;
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UpsampleMode {
+    Nearest,
+    // align_corners = false
+    Bilinear,
+}
+
+// B*HWC => B*(sH)(sW)C
+pub struct Upsample {
+    pub in_channels: usize,
+    pub im_row: usize,
+    pub im_col: usize,
+    pub scale: usize,
+    pub mode: UpsampleMode,
+
+    pub feat_row: usize,
+    pub feat_col: usize,
+
+    // source index pair and weight of the second one for every output row / col
+    row_taps: Vec<(usize, usize, f32)>,
+    col_taps: Vec<(usize, usize, f32)>,
+}
+
+impl Upsample {
+    pub unsafe fn new(
+        in_channels: usize,
+        im_row: usize,
+        im_col: usize,
+        scale: usize,
+        mode: UpsampleMode,
+    ) -> Self {
+        Self {
+            in_channels,
+            im_row,
+            im_col,
+            scale,
+            mode,
+            feat_row: im_row * scale,
+            feat_col: im_col * scale,
+            row_taps: Self::taps(im_row, scale, mode),
+            col_taps: Self::taps(im_col, scale, mode),
+        }
+    }
+
+    fn taps(len: usize, scale: usize, mode: UpsampleMode) -> Vec<(usize, usize, f32)> {
+        (0..len * scale)
+            .map(|dst| match mode {
+                UpsampleMode::Nearest => (dst / scale, dst / scale, 0.0),
+                UpsampleMode::Bilinear => {
+                    let src = ((dst as f32 + 0.5) / scale as f32 - 0.5).max(0.0);
+                    let lo = (src as usize).min(len - 1);
+                    let hi = (lo + 1).min(len - 1);
+                    (lo, hi, src - lo as f32)
+                }
+            })
+            .collect()
+    }
+}
+
+impl nn_trait::Layer for Upsample {
+    fn forward(&mut self, input: Matrix) -> Matrix {
+        unsafe {
+            let (h, w) = input.shape();
+            if w != self.im_row * self.im_col * self.in_channels {
+                panic!("call Upsample with unmatched input shape");
+            }
+            let c = self.in_channels;
+            let ret = Matrix::new(h, self.feat_row * self.feat_col * c);
+            (0..h).into_par_iter().for_each(|idx| {
+                let src = input.row_at(idx as isize);
+                let dst = ret.row_at(idx as isize);
+                for (i, &(r0, r1, lr)) in self.row_taps.iter().enumerate() {
+                    for (j, &(c0, c1, lc)) in self.col_taps.iter().enumerate() {
+                        let a00 = src.add((r0 * self.im_col + c0) * c);
+                        let a01 = src.add((r0 * self.im_col + c1) * c);
+                        let a10 = src.add((r1 * self.im_col + c0) * c);
+                        let a11 = src.add((r1 * self.im_col + c1) * c);
+                        let out = dst.add((i * self.feat_col + j) * c);
+                        for k in 0..c {
+                            let top = *a00.add(k) * (1.0 - lc) + *a01.add(k) * lc;
+                            let bottom = *a10.add(k) * (1.0 - lc) + *a11.add(k) * lc;
+                            *out.add(k) = top * (1.0 - lr) + bottom * lr;
+                        }
+                    }
+                }
+            });
+            ret
+        }
+    }
+    fn backward(&mut self, dLoss: Matrix) -> Matrix {
+        unsafe {
+            let h = dLoss.number_of_row();
+            let c = self.in_channels;
+            let ret = zeros(h, self.im_row * self.im_col * c);
+            (0..h).into_par_iter().for_each(|idx| {
+                let src = dLoss.row_at(idx as isize);
+                let dst = ret.row_at(idx as isize);
+                for (i, &(r0, r1, lr)) in self.row_taps.iter().enumerate() {
+                    for (j, &(c0, c1, lc)) in self.col_taps.iter().enumerate() {
+                        let d = src.add((i * self.feat_col + j) * c);
+                        let a00 = dst.add((r0 * self.im_col + c0) * c);
+                        let a01 = dst.add((r0 * self.im_col + c1) * c);
+                        let a10 = dst.add((r1 * self.im_col + c0) * c);
+                        let a11 = dst.add((r1 * self.im_col + c1) * c);
+                        for k in 0..c {
+                            let g = *d.add(k);
+                            *a00.add(k) += g * (1.0 - lr) * (1.0 - lc);
+                            *a01.add(k) += g * (1.0 - lr) * lc;
+                            *a10.add(k) += g * lr * (1.0 - lc);
+                            *a11.add(k) += g * lr * lc;
+                        }
+                    }
+                }
+            });
+            ret
+        }
+    }
+    fn config(&self) -> nn_trait::LayerConfig {
+        nn_trait::LayerConfig::new(
+            "Upsample",
+            &[
+                ("in_channels", self.in_channels),
+                ("im_row", self.im_row),
+                ("im_col", self.im_col),
+                ("scale", self.scale),
+                ("mode", self.mode as usize),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gradcheck::{check_layer, rand_mat};
+    use crate::utils::nn_trait::Layer;
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        unsafe {
+            let mut seed = 9u32;
+            for mode in [UpsampleMode::Nearest, UpsampleMode::Bilinear] {
+                for scale in [2usize, 3] {
+                    let mut upsample = Upsample::new(2, 3, 4, scale, mode);
+                    let x = rand_mat(2, 24, &mut seed);
+                    assert!(check_layer(&mut upsample, &x) < 2e-2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear_matches_half_pixel_centers() {
+        unsafe {
+            let x = Matrix::new(1, 3);
+            for j in 0..3 {
+                *x.row_at(0).add(j) = j as f32;
+            }
+            let y = Upsample::new(1, 1, 3, 2, UpsampleMode::Bilinear).forward(x);
+            let expected = [0.0, 0.25, 0.75, 1.25, 1.75, 2.0];
+            for row in 0..2 {
+                for (j, value) in expected.iter().enumerate() {
+                    assert!((y.at(0, (row * 6 + j) as isize) - value).abs() < 1e-6);
+                }
+            }
+        }
+    }
+}